@@ -0,0 +1,99 @@
+//! Counts the allocations made while registering callbacks,
+//! with and without reserving capacity up front,
+//! and while refilling a queue that already ran,
+//! and compares them to the inline storage of `scoped_stack`.
+//!
+//! Run with `cargo bench --bench capacity`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use scoped::{scoped, scoped_stack, scoped_with_capacity, Deferring, Outcome};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const CALLBACKS: usize = 1000;
+const CYCLES: usize = 10;
+
+fn measure(name: &str, f: impl Fn()) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    println!(
+        "{:<24} {:>6} allocations {:>10?}",
+        name, allocations, elapsed
+    );
+}
+
+fn main() {
+    measure("scoped", || {
+        scoped(|guard| {
+            for i in 0..CALLBACKS {
+                guard.on_scope_exit(i, |i| {
+                    std::hint::black_box(i);
+                });
+            }
+            Some(())
+        });
+    });
+
+    measure("scoped_with_capacity", || {
+        scoped_with_capacity(0, 0, CALLBACKS, |guard| {
+            for i in 0..CALLBACKS {
+                guard.on_scope_exit(i, |i| {
+                    std::hint::black_box(i);
+                });
+            }
+            Some(())
+        });
+    });
+
+    measure("scoped_stack", || {
+        scoped_stack::<CALLBACKS, _>(|guard| {
+            for i in 0..CALLBACKS {
+                guard.on_scope_exit(i, |i| {
+                    std::hint::black_box(i);
+                });
+            }
+            Some(())
+        });
+    });
+
+    // After the first cycle only the callbacks themselves allocate,
+    // as running the queue keeps its buffer around for the next cycle.
+    measure("execute and refill", || {
+        let mut deferring = Deferring::new();
+        for _ in 0..CYCLES {
+            for i in 0..CALLBACKS {
+                deferring.push(i, |i| {
+                    std::hint::black_box(i);
+                });
+            }
+            deferring.execute(Outcome::Success);
+        }
+    });
+}
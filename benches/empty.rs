@@ -0,0 +1,40 @@
+//! Measures the overhead of a scope on which no callbacks are scheduled,
+//! next to one with a single exit callback.
+//!
+//! Run with `cargo bench --bench empty`.
+use std::hint::black_box;
+use std::time::Instant;
+
+use scoped::scoped;
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn measure(name: &str, f: impl Fn()) {
+    // Warm up, so the first measurement is not skewed.
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_scope = start.elapsed() / ITERATIONS;
+
+    println!("{:<24} {:>8?} per scope", name, per_scope);
+}
+
+fn main() {
+    measure("empty scope", || {
+        black_box(scoped(|guard| black_box(Some(guard as *mut _))));
+    });
+
+    measure("one exit callback", || {
+        black_box(scoped(|guard| {
+            guard.on_scope_exit_fn(|| {
+                black_box(());
+            });
+            Some(())
+        }));
+    });
+}
@@ -0,0 +1,196 @@
+//! This crate provides the `#[derive(Failure)]` macro for the `scoped` crate.
+//!
+//! Don't depend on this crate directly, use the re-export from `scoped` instead.
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+/// Derives `scoped::Failure` for an enum.
+///
+/// Variants annotated with `#[failure]` are failures, all other variants are successes.
+#[proc_macro_derive(Failure, attributes(failure))]
+pub fn derive_failure(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(tokens) => tokens,
+        Err(msg) => format!("compile_error!({:?});", msg).parse().unwrap(),
+    }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, String> {
+    let mut tokens = input.into_iter().peekable();
+
+    // Skip the outer attributes and the visibility.
+    loop {
+        match tokens.peek() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+                tokens.next();
+                tokens.next();
+            }
+            Some(TokenTree::Ident(i)) if i.to_string() == "pub" => {
+                tokens.next();
+                if let Some(TokenTree::Group(g)) = tokens.peek() {
+                    if g.delimiter() == Delimiter::Parenthesis {
+                        tokens.next();
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+
+    match tokens.next() {
+        Some(TokenTree::Ident(i)) if i.to_string() == "enum" => {}
+        _ => return Err("`Failure` can only be derived for enums".into()),
+    }
+
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        _ => return Err("expected the name of the enum".into()),
+    };
+
+    // Collect the generic parameters, without their angle brackets.
+    let mut generics = Vec::new();
+    if let Some(TokenTree::Punct(p)) = tokens.peek() {
+        if p.as_char() == '<' {
+            tokens.next();
+            let mut depth = 1;
+            for tt in tokens.by_ref() {
+                if let TokenTree::Punct(p) = &tt {
+                    match p.as_char() {
+                        '<' => depth += 1,
+                        '>' if !ends_with_joint_minus(&generics) => depth -= 1,
+                        _ => {}
+                    }
+                }
+                if depth == 0 {
+                    break;
+                }
+                generics.push(tt);
+            }
+        }
+    }
+
+    // Everything up to the body is the where clause.
+    let mut where_clause = Vec::new();
+    let body = loop {
+        match tokens.next() {
+            Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => break g,
+            Some(tt) => where_clause.push(tt),
+            None => return Err("expected the body of the enum".into()),
+        }
+    };
+
+    let mut arms = String::new();
+    for variant in split_commas(body.stream().into_iter().collect(), false) {
+        let (is_failure, name) = parse_variant(variant)?;
+        arms += &format!("Self::{} {{ .. }} => {},", name, is_failure);
+    }
+
+    let (impl_generics, type_generics) = split_generics(generics);
+
+    let output = format!(
+        "impl<{}> ::scoped::Failure for {}<{}> {} {{
+            fn is_error(&self) -> bool {{
+                match *self {{ {} }}
+            }}
+        }}",
+        impl_generics,
+        name,
+        type_generics,
+        stringify(where_clause),
+        arms,
+    );
+
+    output
+        .parse()
+        .map_err(|_| "failed to expand `Failure`".into())
+}
+
+/// Returns whether `variant` is marked with `#[failure]`, along with its name.
+fn parse_variant(variant: Vec<TokenTree>) -> Result<(bool, String), String> {
+    let mut is_failure = false;
+    let mut tokens = variant.into_iter();
+
+    loop {
+        match tokens.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+                let attr = match tokens.next() {
+                    Some(TokenTree::Group(g)) => g.stream().to_string(),
+                    _ => return Err("expected an attribute".into()),
+                };
+
+                if attr == "failure" {
+                    is_failure = true;
+                } else if attr.starts_with("failure") {
+                    return Err("`#[failure]` does not take arguments".into());
+                }
+            }
+            Some(TokenTree::Ident(i)) => return Ok((is_failure, i.to_string())),
+            _ => return Err("expected a variant".into()),
+        }
+    }
+}
+
+/// Splits the generic parameters into the parameters of the impl and the arguments of the type.
+fn split_generics(generics: Vec<TokenTree>) -> (String, String) {
+    let mut impl_generics = Vec::new();
+    let mut type_generics = Vec::new();
+
+    for param in split_commas(generics, true) {
+        // Defaults are not allowed on impls.
+        let param: Vec<_> = param
+            .into_iter()
+            .take_while(|tt| !matches!(tt, TokenTree::Punct(p) if p.as_char() == '='))
+            .collect();
+
+        let arg = match param.as_slice() {
+            [TokenTree::Punct(p), TokenTree::Ident(i), ..] if p.as_char() == '\'' => {
+                format!("'{}", i)
+            }
+            [TokenTree::Ident(c), TokenTree::Ident(i), ..] if c.to_string() == "const" => {
+                i.to_string()
+            }
+            [TokenTree::Ident(i), ..] => i.to_string(),
+            _ => continue,
+        };
+
+        impl_generics.push(stringify(param));
+        type_generics.push(arg);
+    }
+
+    (impl_generics.join(", "), type_generics.join(", "))
+}
+
+/// Splits `tokens` at the top level commas, skipping empty pieces.
+/// With `angles` set, commas inside of angle brackets, like the ones in `T: Trait<A, B>`, are not split on.
+fn split_commas(tokens: Vec<TokenTree>, angles: bool) -> Vec<Vec<TokenTree>> {
+    let mut pieces = vec![Vec::new()];
+    let mut depth = 0;
+
+    for tt in tokens {
+        if let TokenTree::Punct(p) = &tt {
+            match p.as_char() {
+                '<' if angles => depth += 1,
+                // Don't mistake the `>` of a `->` for a closing bracket.
+                '>' if angles && !ends_with_joint_minus(pieces.last().unwrap()) => depth -= 1,
+                ',' if depth == 0 => {
+                    pieces.push(Vec::new());
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        pieces.last_mut().unwrap().push(tt);
+    }
+
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+fn ends_with_joint_minus(tokens: &[TokenTree]) -> bool {
+    matches!(tokens.last(), Some(TokenTree::Punct(p)) if p.as_char() == '-' && p.spacing() == Spacing::Joint)
+}
+
+fn stringify(tokens: Vec<TokenTree>) -> String {
+    tokens.into_iter().collect::<TokenStream>().to_string()
+}
@@ -0,0 +1,259 @@
+//! Scopes whose callbacks can fail.
+use crate::{allocate, DeferCallback, Depth, Failure, Outcome, Scheduled};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// Like `Defer`, but for callbacks that return an error.
+trait TryDefer<E> {
+    fn try_call(self: Box<Self>, outcome: Outcome) -> Result<(), E>;
+}
+
+impl<F: FnOnce(T, Outcome) -> Result<(), E>, T, E> TryDefer<E> for DeferCallback<T, F> {
+    fn try_call(self: Box<Self>, outcome: Outcome) -> Result<(), E> {
+        (self.call_fn)(self.item, outcome)
+    }
+}
+
+struct TryDeferring<'a, E> {
+    inner: RefCell<Vec<Scheduled<dyn TryDefer<E> + 'a>>>,
+}
+
+impl<'a, E> Default for TryDeferring<'a, E> {
+    fn default() -> Self {
+        Self {
+            inner: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl<'a, E: 'a> TryDeferring<'a, E> {
+    #[allow(clippy::mut_from_ref)]
+    fn push<T: 'a>(&self, item: T, closure: impl FnOnce(T) -> Result<(), E> + 'a) -> &mut T {
+        let (raw, item) = allocate(DeferCallback::new(item, move |item, _| closure(item)));
+        let deferred = unsafe { Scheduled::<dyn TryDefer<E> + 'a>::from_raw(raw) };
+        self.inner.borrow_mut().push(deferred);
+
+        // See `Deferring::schedule_with_outcome`, the same reasoning applies here.
+        unsafe { &mut *item.as_ptr() }
+    }
+
+    /// Runs all callbacks, pushing the errors they return onto `errors`.
+    fn execute(&mut self, outcome: Outcome, errors: &mut Vec<E>) {
+        let v = core::mem::take(self.inner.get_mut());
+        for d in v.into_iter().rev() {
+            if let Err(e) = d.into_box().try_call(outcome) {
+                errors.push(e);
+            }
+        }
+    }
+}
+
+/// A guard whose callbacks return a `Result<(), E>`.
+///
+/// An error returned by one callback does not stop the others from running,
+/// instead all errors are collected and handed back by [`try_scoped`].
+pub struct TryGuard<'a, E> {
+    on_scope_success: TryDeferring<'a, E>,
+    on_scope_failure: TryDeferring<'a, E>,
+    on_scope_exit: TryDeferring<'a, E>,
+}
+
+impl<'a, E> Default for TryGuard<'a, E> {
+    fn default() -> Self {
+        Self {
+            on_scope_success: TryDeferring::default(),
+            on_scope_failure: TryDeferring::default(),
+            on_scope_exit: TryDeferring::default(),
+        }
+    }
+}
+
+impl<'a, E: 'a> TryGuard<'a, E> {
+    /// Schedules fallible defered closure `dc` to run on a scope's success.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) -> Result<(), E> + 'a,
+    ) -> &mut T {
+        self.on_scope_success.push(item, dc)
+    }
+
+    /// Schedules fallible defered closure `dc` to run on a scope's exit.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) -> Result<(), E> + 'a,
+    ) -> &mut T {
+        self.on_scope_exit.push(item, dc)
+    }
+
+    /// Schedules fallible defered closure `dc` to run on a scope's failure.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) -> Result<(), E> + 'a,
+    ) -> &mut T {
+        self.on_scope_failure.push(item, dc)
+    }
+
+    fn finish(&mut self, outcome: Outcome) -> Vec<E> {
+        let mut errors = Vec::new();
+
+        match outcome {
+            Outcome::Success => {
+                self.on_scope_failure = TryDeferring::default();
+                self.on_scope_success.execute(outcome, &mut errors);
+            }
+            Outcome::Failure => self.on_scope_failure.execute(outcome, &mut errors),
+            Outcome::Indeterminate => {
+                self.on_scope_success = TryDeferring::default();
+                self.on_scope_failure = TryDeferring::default();
+            }
+        }
+
+        self.on_scope_exit.execute(outcome, &mut errors);
+        errors
+    }
+}
+
+/// Runs the remaining failure and exit callbacks when dropped, see `PanicGuard`.
+/// Any errors they return are discarded, as there is no one left to hand them to.
+struct TryPanicGuard<'a, E: 'a> {
+    guard: TryGuard<'a, E>,
+}
+
+impl<'a, E: 'a> Drop for TryPanicGuard<'a, E> {
+    fn drop(&mut self) {
+        let mut errors = Vec::new();
+        self.guard
+            .on_scope_failure
+            .execute(Outcome::Failure, &mut errors);
+        self.guard
+            .on_scope_exit
+            .execute(Outcome::Failure, &mut errors);
+    }
+}
+
+/// Executes the scope `scope` with a [`TryGuard`], whose callbacks may fail.
+///
+/// Returns the value `scope` returned, along with the errors of all callbacks that failed,
+/// in the order the callbacks ran.
+///
+/// # Examples
+/// ```
+/// use scoped::try_scoped;
+///
+/// let (ret, errors) = try_scoped(|guard| {
+///     guard.on_scope_exit("a.txt", |_| Err("could not flush a.txt"));
+///     guard.on_scope_exit("b.txt", |_| Ok(()));
+///     Some(1)
+/// });
+///
+/// assert_eq!(ret, Some(1));
+/// assert_eq!(errors, vec!["could not flush a.txt"]);
+/// ```
+pub fn try_scoped<'a, R: Failure, E: 'a>(
+    scope: impl FnOnce(&mut TryGuard<'a, E>) -> R,
+) -> (R, Vec<E>) {
+    let _depth = Depth::enter();
+    let mut panic_guard = TryPanicGuard {
+        guard: TryGuard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+    let errors = guard.finish(Outcome::of(&ret));
+    (ret, errors)
+}
+
+/// Executes the scope `scope` with a [`TryGuard`], folding the errors of the callbacks into the returned result,
+/// so they can be propagated with `?`.
+///
+/// The error of the scope takes precedence: if the scope returned an `Err`, it is returned as is,
+/// and the errors of the callbacks are dropped. Otherwise, the first error a callback returned,
+/// in the order the callbacks ran, replaces the scope's `Ok`. Later callbacks still run, but their errors are dropped.
+/// Use [`try_scoped`] to get hold of all errors.
+///
+/// # Examples
+/// ```
+/// use scoped::try_scoped_result;
+///
+/// fn save() -> Result<u32, &'static str> {
+///     let written = try_scoped_result(|guard| {
+///         guard.on_scope_exit("out.txt", |_| Err("could not flush out.txt"));
+///         Ok(42)
+///     })?;
+///     Ok(written)
+/// }
+///
+/// assert_eq!(save(), Err("could not flush out.txt"));
+/// ```
+pub fn try_scoped_result<'a, T, E: 'a>(
+    scope: impl FnOnce(&mut TryGuard<'a, E>) -> Result<T, E>,
+) -> Result<T, E> {
+    let (ret, errors) = try_scoped(scope);
+    let value = ret?;
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn test_collect_errors() {
+        let (ret, errors) = try_scoped(|guard| {
+            guard.on_scope_success(1, Err);
+            guard.on_scope_failure(2, Err);
+            guard.on_scope_exit(3, Err);
+            guard.on_scope_exit(4, |_| Ok(()));
+            guard.on_scope_exit(5, Err);
+            Err::<(), _>(())
+        });
+
+        assert_eq!(ret, Err(()));
+        assert_eq!(errors, vec![2, 5, 3]);
+    }
+
+    #[test]
+    fn test_propagate_errors() {
+        let cleanup_failed = try_scoped_result(|guard| {
+            guard.on_scope_exit(1, Err);
+            guard.on_scope_exit(2, Err);
+            Ok(())
+        });
+        assert_eq!(cleanup_failed, Err(2));
+
+        let both_failed = try_scoped_result(|guard| {
+            guard.on_scope_failure(1, Err);
+            Err::<(), _>(0)
+        });
+        assert_eq!(both_failed, Err(0));
+
+        let succeeded = try_scoped_result(|guard| {
+            guard.on_scope_exit(1, |_| Ok(()));
+            Ok::<_, i32>(5)
+        });
+        assert_eq!(succeeded, Ok(5));
+    }
+
+    #[test]
+    fn test_no_errors() {
+        let (ret, errors) = try_scoped(|guard| {
+            guard.on_scope_success((), |_| Ok::<_, ()>(()));
+            true
+        });
+
+        assert!(ret);
+        assert!(errors.is_empty());
+    }
+}
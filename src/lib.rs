@@ -3,18 +3,87 @@
 //!
 //! This is different than the ScopeGuard crate does,
 //! because here it's dependent on the scope's outcome which callbacks should run.
-use std::cell::RefCell;
+//!
+//! # Features
+//! - `std` (default): enables [`scoped_catch_unwind`], which needs the unwinding machinery of `std`,
+//!   [`SyncGuard`], which needs `std`'s `Mutex`, [`scoped_timed`] and [`Guard::on_scope_exit_if_elapsed`],
+//!   which need `std`'s `Instant`, and [`current_depth`], which needs thread locals.
+//!   Without it the crate is `#![no_std]`, and only depends on `core` and `alloc`.
+//! - `derive` (default): enables `#[derive(Failure)]`.
+//! - `trace`: enables [`set_trace_hook`], to observe every callback as it runs.
+//!   Without it, running a callback costs nothing beyond calling it.
+#![no_std]
+
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
+use alloc::borrow::{Cow, ToOwned};
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::{Cell, OnceCell, RefCell};
+use core::convert::Infallible;
+use core::fmt::Debug;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::ops::{ControlFlow, RangeInclusive};
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Poll;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+
+#[cfg(feature = "derive")]
+pub use scoped_derive::Failure;
+
+pub mod prelude;
+
+mod fallible;
+pub use fallible::{try_scoped, try_scoped_result, TryGuard};
+
+mod stack;
+pub use stack::{scoped_stack, StackGuard};
+
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "trace")]
+pub use trace::{set_trace_hook, CallbackEvent};
 
-trait Defer {
-    fn call(self: Box<Self>);
+#[cfg(feature = "std")]
+mod sync;
+#[cfg(feature = "std")]
+pub use sync::{scoped_detached, scoped_sync, SyncGuard};
+
+/// A deferred callback, as kept by a [`Deferring`].
+///
+/// The scheduling methods wrap a closure and its item into a `Defer` of their own.
+/// Implement this trait to schedule other kinds of callbacks with [`Deferring::push_boxed`].
+///
+/// The queue calls [`call`](Defer::call) at most once, consuming the callback.
+/// If the queue never runs it, for example because it is cleared, or because it belongs to the phase
+/// that does not run, the callback is dropped without being called.
+pub trait Defer {
+    /// Runs the callback, with the outcome the queue is executed for.
+    fn call(self: Box<Self>, outcome: Outcome);
 }
 
-impl<F: FnMut(T), T> Defer for DeferCallback<T, F> {
-    fn call(mut self: Box<Self>) {
-        (self.call_fn)(self.item);
+impl<F: FnOnce(T, Outcome), T> Defer for DeferCallback<T, F> {
+    fn call(self: Box<Self>, outcome: Outcome) {
+        (self.call_fn)(self.item, outcome);
     }
 }
 
+/// A closure along with its item.
+///
+/// If the callback is dropped without being called, the fields drop in declaration order:
+/// the item first, then the closure. Don't reorder them, this order is documented on [`Guard`].
 #[derive(Debug)]
 struct DeferCallback<T, F> {
     item: T,
@@ -27,205 +96,5989 @@ impl<T, F> DeferCallback<T, F> {
     }
 }
 
-#[derive(Default)]
-pub struct Deferring<'a> {
-    inner: RefCell<Vec<Box<dyn Defer + 'a>>>,
+/// A result along with the closures to call with its value, see [`Guard::on_result`].
+struct OnResult<T, E, F, G> {
+    result: Result<T, E>,
+    on_ok: F,
+    on_err: G,
+}
+
+impl<T, E, F: FnOnce(T), G: FnOnce(E)> Defer for OnResult<T, E, F, G> {
+    fn call(self: Box<Self>, _: Outcome) {
+        let this = *self;
+        match this.result {
+            Ok(value) => (this.on_ok)(value),
+            Err(error) => (this.on_err)(error),
+        }
+    }
 }
 
-unsafe fn extend_lifetime_mut<'a, 'b, T: ?Sized>(x: &'a mut T) -> &'b mut T {
-    std::mem::transmute(x)
+/// An owned, heap allocated callback.
+///
+/// This is a `Box` that is kept as a raw pointer while the callback is pending.
+/// Moving a `Box` asserts unique access to its allocation, which would invalidate the references
+/// into the item that are handed out when scheduling. A raw pointer makes no such claim,
+/// so those references stay valid while the queue moves its entries around, for example when it reallocates.
+struct Scheduled<D: ?Sized> {
+    ptr: NonNull<D>,
 }
 
-impl<'a> Deferring<'a> {
-    fn new() -> Self {
+impl<D: ?Sized> Scheduled<D> {
+    /// Takes ownership of `ptr`, which must come from [`Box::into_raw`].
+    unsafe fn from_raw(ptr: *mut D) -> Self {
         Self {
-            inner: RefCell::new(Vec::new()),
+            ptr: NonNull::new_unchecked(ptr),
         }
     }
 
-    fn push<T: 'a>(&self, item: T, closure: impl FnMut(T) + 'a) -> &'a mut T {
-        let mut deferred = Box::new(DeferCallback::new(item, closure));
-
-        // This operation is safe,
-        // `deferred` is stored on the heap, moving the box does not invalidate pointers to the internals,
-        // and we never touch the box internals again without &mut self.
-        // Rust can't prove this, so in order to return a mutable reference to T,
-        // we need to `unsafely` `extend` the lifetime of the borrow.
-        let ret = unsafe { extend_lifetime_mut(&mut deferred.item) };
-        self.inner.borrow_mut().push(deferred);
-        ret
+    /// Turns this back into a `Box`, which must only happen once no references into the item are alive.
+    fn into_box(self) -> Box<D> {
+        let ptr = self.ptr.as_ptr();
+        core::mem::forget(self);
+        unsafe { Box::from_raw(ptr) }
     }
+}
 
-    fn execute(mut self) {
-        let v = std::mem::replace(self.inner.get_mut(), vec![]);
-        for d in v.into_iter().rev() {
-            d.call();
-        }
+impl<D: ?Sized> Drop for Scheduled<D> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.ptr.as_ptr()) });
     }
 }
 
-/// A guard is a handle to schedule callbacks on, from an outer scope.
+// `Scheduled<D>` owns a `D`, just like `Box<D>` does.
+unsafe impl<D: ?Sized + Send> Send for Scheduled<D> {}
+unsafe impl<D: ?Sized + Sync> Sync for Scheduled<D> {}
+
+/// Moves `deferred` to the heap, returning the allocation along with a pointer to its item.
+/// The allocation should be handed to [`Scheduled::from_raw`].
+fn allocate<T, F>(deferred: DeferCallback<T, F>) -> (*mut DeferCallback<T, F>, NonNull<T>) {
+    let raw = Box::into_raw(Box::new(deferred));
+    // `raw` is non-null and valid, as it was just allocated.
+    let item = unsafe { NonNull::new_unchecked(core::ptr::addr_of_mut!((*raw).item)) };
+    (raw, item)
+}
+
+/// A scheduled callback, tagged with the id it was registered under.
+struct Entry<'a> {
+    id: usize,
+    /// Points to the item inside of `deferred`.
+    item: NonNull<()>,
+    /// Formats `item`, if it was scheduled with a `Debug` bound.
+    debug: Option<DebugFn>,
+    /// Entries with a higher priority run first, see [`Guard::on_scope_exit_priority`].
+    priority: i32,
+    /// The key the entry was scheduled under, see [`Guard::on_scope_exit_keyed`].
+    /// It lives and dies with the entry, so it is freed once the entry ran, was cancelled or was dropped.
+    key: Option<Box<dyn Any>>,
+    deferred: Scheduled<dyn Defer + 'a>,
+}
+
+/// Formats the item behind an [`Entry`], which must be of the type the function was instantiated with.
+type DebugFn = unsafe fn(NonNull<()>) -> String;
+
+unsafe fn debug_item<T: Debug>(item: NonNull<()>) -> String {
+    alloc::format!("{:?}", item.cast::<T>().as_ref())
+}
+
+/// The source of callback ids. Ids are unique across all guards,
+/// so an id can't be mistaken for an entry of a different guard.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A queue of deferred callbacks, the building block of a [`Guard`], which holds one per phase.
+///
+/// `Deferring` can be used on its own to build custom scope drivers:
+/// [`push`](Deferring::push) callbacks onto it, and [`execute`](Deferring::execute) them when appropriate.
+///
+/// # Examples
+/// ```
+/// use scoped::{Deferring, Outcome};
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+///
+/// let mut deferring = Deferring::new();
+/// deferring.push(1, |n| log.borrow_mut().push(n));
+/// *deferring.push(0, |n| log.borrow_mut().push(n)) = 2;
+///
+/// deferring.execute(Outcome::Success);
+/// assert_eq!(*log.borrow(), vec![2, 1]);
+/// ```
 #[derive(Default)]
-pub struct Guard<'a> {
-    /// Callbacks to be run on a scope's success.
-    on_scope_success: Deferring<'a>,
+pub struct Deferring<'a> {
+    inner: RefCell<Vec<Entry<'a>>>,
+    order: Cell<Order>,
+    /// Whether a panicking callback is caught, so the remaining ones still run.
+    #[cfg(feature = "std")]
+    catch_panics: Cell<bool>,
+    /// Whether a caught panic is only counted, rather than resumed once the remaining callbacks ran.
+    #[cfg(feature = "std")]
+    count_panics: Cell<bool>,
+    /// The number of panics caught so far.
+    #[cfg(feature = "std")]
+    panics: Cell<usize>,
+    /// Whether dropping the queue with pending callbacks is a bug, see [`Deferring::arm`].
+    armed: Cell<bool>,
+}
 
-    /// Callbacks to be run on a scope's failure.
-    on_scope_failure: Deferring<'a>,
+impl<'a> Drop for Deferring<'a> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            return;
+        }
 
-    /// Callbacks to be run on a scope's exit.
-    on_scope_exit: Deferring<'a>,
+        let pending = self.inner.get_mut().len();
+        if cfg!(debug_assertions) && self.armed.get() && pending > 0 {
+            panic!(
+                "an armed `Deferring` was dropped with {} callbacks that never ran",
+                pending
+            );
+        }
+    }
 }
 
-impl<'a> Guard<'a> {
-    /// Schedules defered closure `dc` to run on a scope's success.
+impl<'a> Deferring<'a> {
+    /// Creates an empty queue.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        let deferring = Self::default();
+        deferring.inner.borrow_mut().reserve(capacity);
+        deferring
+    }
+
+    /// Arms the queue, declaring that it must be executed before it is dropped.
+    ///
+    /// In debug builds, dropping an armed queue that still holds callbacks panics,
+    /// instead of silently dropping them. This catches drivers with a path that forgets to execute the queue.
+    /// The check is skipped in release builds, and, with the `std` feature, if the thread is already panicking.
+    ///
+    /// # Examples
+    /// ```should_panic
+    /// use scoped::Deferring;
+    ///
+    /// let deferring = Deferring::new();
+    /// deferring.arm();
+    /// deferring.push((), |()| println!("never runs"));
+    /// drop(deferring);
+    /// ```
+    pub fn arm(&self) {
+        self.armed.set(true);
+    }
+
+    /// Schedules `closure` to be called with `item` once the queue is executed.
+    /// Returns a mutable reference to the scheduled item.
+    ///
+    /// # Aliasing
+    /// The item lives on the heap, so it stays put while more callbacks get pushed,
+    /// and every item gets a reference of its own. The returned reference borrows `self` though,
+    /// so it must be gone before [`execute`](Deferring::execute) moves the item into its callback,
+    /// which the borrow checker enforces by `execute` taking `&mut self`:
+    /// ```compile_fail
+    /// use scoped::{Deferring, Outcome};
+    ///
+    /// let mut deferring = Deferring::new();
+    /// let v = deferring.push(vec![1], drop);
+    /// deferring.execute(Outcome::Success);
+    /// v.push(2); // `v` was moved into `drop` already
+    /// ```
     #[allow(clippy::mut_from_ref)]
-    pub fn on_scope_success<T: 'a>(&self, item: T, dc: impl FnMut(T) + 'a) -> &mut T {
-        self.on_scope_success.push(item, dc)
+    pub fn push<T: 'a>(&self, item: T, closure: impl FnOnce(T) + 'a) -> &mut T {
+        self.schedule(item, closure).1
     }
 
-    /// Schedules defered closure `dc` to run on a scope's exit.
+    /// Schedules a callback of your own, which is called with the outcome once the queue is executed.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::{Defer, Deferring, Outcome};
+    ///
+    /// struct Announce(&'static str);
+    ///
+    /// impl Defer for Announce {
+    ///     fn call(self: Box<Self>, outcome: Outcome) {
+    ///         println!("{} finished with {:?}", self.0, outcome);
+    ///     }
+    /// }
+    ///
+    /// let mut deferring = Deferring::new();
+    /// deferring.push_boxed(Box::new(Announce("download")));
+    /// deferring.execute(Outcome::Success);
+    /// ```
+    pub fn push_boxed(&self, deferred: Box<dyn Defer + 'a>) {
+        let deferred = unsafe { Scheduled::from_raw(Box::into_raw(deferred)) };
+        self.inner.borrow_mut().push(Entry {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            // There is no item, and no one asks for it, as no id was handed out.
+            item: NonNull::dangling(),
+            debug: None,
+            priority: 0,
+            key: None,
+            deferred,
+        });
+    }
+
+    /// Schedules `closure`, returning the id of the new entry and a reference to the scheduled item.
+    fn schedule<T: 'a>(&self, item: T, closure: impl FnOnce(T) + 'a) -> (usize, &mut T) {
+        self.schedule_with_outcome(item, move |item, _| closure(item))
+    }
+
+    /// Like [`Deferring::schedule`], but `closure` also receives the outcome of the scope.
     #[allow(clippy::mut_from_ref)]
-    pub fn on_scope_exit<T: 'a>(&self, item: T, dc: impl FnMut(T) + 'a) -> &mut T {
-        self.on_scope_exit.push(item, dc)
+    fn schedule_with_outcome<T: 'a>(
+        &self,
+        item: T,
+        closure: impl FnOnce(T, Outcome) + 'a,
+    ) -> (usize, &mut T) {
+        self.schedule_entry(item, closure, None)
+    }
+
+    /// Like [`Deferring::schedule`], but the item shows up in [`Deferring::debug_items`].
+    fn schedule_debug<T: Debug + 'a>(&self, item: T, closure: impl FnOnce(T) + 'a) -> &mut T {
+        self.schedule_entry(item, move |item, _| closure(item), Some(debug_item::<T>))
+            .1
     }
 
-    /// Schedules defered closure `dc` to run on a scope's failure.
     #[allow(clippy::mut_from_ref)]
-    pub fn on_scope_failure<T: 'a>(&self, item: T, dc: impl FnMut(T) + 'a) -> &mut T {
-        self.on_scope_failure.push(item, dc)
+    fn schedule_entry<T: 'a>(
+        &self,
+        item: T,
+        closure: impl FnOnce(T, Outcome) + 'a,
+        debug: Option<DebugFn>,
+    ) -> (usize, &mut T) {
+        let (raw, item) = allocate(DeferCallback::new(item, closure));
+        let deferred = unsafe { Scheduled::<dyn Defer + 'a>::from_raw(raw) };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.inner.borrow_mut().push(Entry {
+            id,
+            item: item.cast(),
+            debug,
+            priority: 0,
+            key: None,
+            deferred,
+        });
+
+        // The item stays where it is until the entry is executed or dropped,
+        // both of which require `&mut self`, while the returned reference borrows `self`.
+        // Nothing else touches the item in the meantime, so the reference is unique.
+        (id, unsafe { &mut *item.as_ptr() })
+    }
+
+    /// Sets the priority of the entry with id `id`, which was scheduled last.
+    fn prioritize(&self, id: usize, priority: i32) {
+        if let Some(entry) = self
+            .inner
+            .borrow_mut()
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.id == id)
+        {
+            entry.priority = priority;
+        }
+    }
+
+    /// Returns a pointer to the item of the entry with id `id`, if it is still pending.
+    fn item(&self, id: usize) -> Option<NonNull<()>> {
+        self.inner
+            .borrow()
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| entry.item)
+    }
+
+    /// Removes the entry with id `id` without calling it.
+    /// Returns true if such an entry was still pending.
+    fn cancel(&mut self, id: usize) -> bool {
+        let inner = self.inner.get_mut();
+        match inner.iter().position(|entry| entry.id == id) {
+            Some(idx) => {
+                inner.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the key of the entry with id `id`, which was scheduled last.
+    fn set_key(&self, id: usize, key: Box<dyn Any>) {
+        if let Some(entry) = self
+            .inner
+            .borrow_mut()
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.id == id)
+        {
+            entry.key = Some(key);
+        }
+    }
+
+    /// Drops the entries scheduled under `key`, without calling them.
+    fn cancel_keyed<K: Eq + 'static>(&mut self, key: &K) {
+        self.inner
+            .get_mut()
+            .retain(|entry| entry.key.as_ref().and_then(|k| k.downcast_ref()) != Some(key));
+    }
+
+    /// Moves all entries of `other` to the back of `self`.
+    fn append(&self, other: Deferring<'a>) {
+        self.inner.borrow_mut().append(&mut other.inner.take());
+    }
+
+    /// Drops all entries without calling them.
+    fn clear(&mut self) {
+        self.inner.get_mut().clear();
+    }
+
+    /// Formats the pending items in the order they were scheduled,
+    /// with `None` for items that were scheduled without a `Debug` bound.
+    ///
+    /// This takes `&mut self`, so no reference returned by the scheduling methods is alive while reading the items.
+    fn debug_items(&mut self) -> impl Iterator<Item = Option<String>> + '_ {
+        self.inner.get_mut().iter().map(|entry| {
+            // `debug` was instantiated with the type of `item`, when the entry was scheduled.
+            entry.debug.map(|debug| unsafe { debug(entry.item) })
+        })
+    }
+
+    /// Returns the number of pending callbacks.
+    fn len(&self) -> usize {
+        self.inner.borrow().len()
+    }
+
+    /// Runs all callbacks pushed so far, leaving the queue empty.
+    /// By default the callback pushed last runs first.
+    ///
+    /// Callbacks that are interested in the outcome, which can only be scheduled through a [`Guard`], receive `outcome`.
+    pub fn execute(&mut self, outcome: Outcome) {
+        self.run(outcome, None);
+    }
+
+    /// Runs all callbacks like [`Deferring::execute`] does, returning how many of them ran.
+    pub fn execute_and_report(&mut self, outcome: Outcome) -> usize {
+        self.run(outcome, None)
+    }
+
+    /// Runs all callbacks, like [`Deferring::execute`], on behalf of the phase `when` of a guard.
+    /// Returns how many callbacks ran.
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    fn run(&mut self, outcome: Outcome, when: Option<When>) -> usize {
+        // Skip the setup below for the common case of a phase without callbacks.
+        if self.inner.get_mut().is_empty() {
+            return 0;
+        }
+
+        #[cfg(feature = "std")]
+        let catch_panics = self.catch_panics.get();
+        #[cfg(feature = "std")]
+        let count_panics = self.count_panics.get();
+        #[cfg(feature = "std")]
+        let mut first_panic = None;
+        #[cfg(feature = "std")]
+        let mut caught = 0;
+
+        let mut call = |(index, entry): (usize, Entry<'a>)| {
+            #[cfg(feature = "trace")]
+            trace::callback(&CallbackEvent {
+                when,
+                index,
+                outcome,
+            });
+
+            #[cfg(feature = "std")]
+            if catch_panics {
+                let call = AssertUnwindSafe(|| entry.deferred.into_box().call(outcome));
+                if let Err(payload) = panic::catch_unwind(call) {
+                    caught += 1;
+                    if !count_panics {
+                        first_panic.get_or_insert(payload);
+                    }
+                }
+                return;
+            }
+
+            entry.deferred.into_box().call(outcome)
+        };
+
+        let mut v = core::mem::take(self.inner.get_mut());
+        let ran = v.len();
+
+        // Sort stably, so entries of the same priority keep running in order.
+        if v.iter().any(|entry| entry.priority != 0) {
+            match self.order.get() {
+                Order::Lifo => v.sort_by_key(|entry| entry.priority),
+                Order::Fifo => v.sort_by_key(|entry| core::cmp::Reverse(entry.priority)),
+            }
+        }
+
+        match self.order.get() {
+            Order::Lifo => v.drain(..).rev().enumerate().for_each(&mut call),
+            Order::Fifo => v.drain(..).enumerate().for_each(&mut call),
+        }
+
+        // Hand the allocation back, so a queue that is reused does not reallocate.
+        *self.inner.get_mut() = v;
+
+        #[cfg(feature = "std")]
+        self.panics.set(self.panics.get() + caught);
+        #[cfg(feature = "std")]
+        if let Some(payload) = first_panic {
+            panic::resume_unwind(payload);
+        }
+        ran
     }
 }
 
-/// A trait to annotate whether a type is `success` or `failure`.
-pub trait Failure {
-    /// Returns true if the type is in a failure state, false otherwise.
-    fn is_error(&self) -> bool;
+/// The order in which callbacks of a single phase run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Order {
+    /// Last in, first out: the callback scheduled last runs first, like destructors do.
+    #[default]
+    Lifo,
+
+    /// First in, first out: callbacks run in the order they were scheduled.
+    Fifo,
 }
 
-impl<T, E> Failure for Result<T, E> {
-    /// `Ok(T)` is success, `Err(E)` is failure.
-    fn is_error(&self) -> bool {
-        self.is_err()
+/// The phase of a scope a callback is scheduled for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum When {
+    /// On the scope's success.
+    Success,
+
+    /// On the scope's failure.
+    Failure,
+
+    /// On the scope's success or failure, after the success or failure callbacks, see [`Guard::on_scope_finish`].
+    Finish,
+
+    /// On the scope's exit, regardless of its outcome.
+    Exit,
+}
+
+/// The outcome of a scope, as decided by the [`Failure`] impl of the value it returned.
+/// A scope that panics has failed.
+///
+/// `Outcome` implements [`Failure`] itself, so a scope that has no natural result type can return one,
+/// converted from a `bool` or a `Result` if need be:
+/// ```
+/// use scoped::{scoped, Outcome};
+///
+/// scoped(|guard| -> Outcome {
+///     guard.on_scope_failure_fn(|| println!("rolling back"));
+///     let applied = std::env::var_os("DRY_RUN").is_none();
+///     applied.into()
+/// });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// The scope succeeded.
+    Success,
+
+    /// The scope failed.
+    Failure,
+
+    /// The scope neither succeeded nor failed, see [`Failure::outcome`].
+    /// Only the exit callbacks run.
+    Indeterminate,
+}
+
+impl Outcome {
+    fn of<R: Failure + ?Sized>(ret: &R) -> Self {
+        ret.outcome()
+    }
+
+    fn failed_if(is_error: bool) -> Self {
+        if is_error {
+            Outcome::Failure
+        } else {
+            Outcome::Success
+        }
     }
 }
 
-impl<T> Failure for Option<T> {
-    /// `Some(T)` is success, `None` is failure.
-    fn is_error(&self) -> bool {
-        self.is_none()
+/// A snapshot of the callbacks pending on a guard, see [`Guard::debug_pending`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingReport {
+    /// The pending callbacks: first the success, then the failure, the finish and finally the exit callbacks,
+    /// each phase in the order its callbacks were scheduled.
+    pub items: Vec<PendingItem>,
+}
+
+/// A pending callback, as listed by a [`PendingReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingItem {
+    /// The phase the callback is scheduled for.
+    pub when: When,
+
+    /// The `Debug` representation of the callback's item,
+    /// or `None` if it was not scheduled with one of the `_debug` methods.
+    pub item: Option<String>,
+}
+
+/// The error returned by [`Guard::try_on_scope_exit`] when the guard has reached its limit.
+/// It hands back the item that could not be scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LimitExceeded<T> {
+    item: T,
+}
+
+impl<T> LimitExceeded<T> {
+    /// Returns the item that could not be scheduled.
+    pub fn into_item(self) -> T {
+        self.item
     }
 }
 
-/// Executes the scope `scope`.
-/// A scope is a closure, in which access to a guard is granted.
-/// A guard is used to schedule callbacks to run on a scope's success, failure, or exit, using
-/// [`Guard::on_scope_success`], [`Guard::on_scope_failure`], [`Guard::on_scope_exit`].
+impl<T> core::fmt::Display for LimitExceeded<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("exceeded the limit of scheduled callbacks")
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Debug> Error for LimitExceeded<T> {}
+
+/// A handle to a scheduled callback, used to [`cancel`](Guard::cancel) it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CallbackId {
+    phase: When,
+    id: usize,
+}
+
+/// A typed handle to a callback scheduled with one of the `_peekable` methods,
+/// used to [`peek`](Guard::peek) at its item.
 ///
-/// Its important to note that callbacks scheduled with [`Guard::on_scope_exit`] will *always* run, and will always run last.
+/// `ItemId` is invariant in `T`, so [`Guard::replace_item`] can't store an item
+/// that lives shorter than the one the callback was scheduled with.
+pub struct ItemId<T> {
+    id: CallbackId,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T> ItemId<T> {
+    /// Returns the [`CallbackId`] of the callback, to [`cancel`](Guard::cancel) it with.
+    pub fn id(&self) -> CallbackId {
+        self.id
+    }
+}
+
+impl<T> Clone for ItemId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ItemId<T> {}
+
+impl<T> core::fmt::Debug for ItemId<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("ItemId").field(&self.id).finish()
+    }
+}
+
+/// A guard is a handle to schedule callbacks on, from an outer scope.
 ///
-/// # Examples
+/// Every scheduled callback is boxed. Besides that, each phase keeps its callbacks in a `Vec`,
+/// which is only allocated once the first callback is scheduled on that phase,
+/// so a default guard on which nothing is scheduled does not allocate at all.
+///
+/// # References to scheduled items
+/// The scheduling methods return a mutable reference into the scheduled item.
+/// That reference borrows the guard, so it can't outlive the scope it was handed out in,
+/// and is certainly gone by the time the callbacks consume their items:
+/// ```compile_fail
+/// use scoped::scoped;
+///
+/// let mut leaked = None;
+/// scoped(|guard| {
+///     leaked = Some(guard.on_scope_exit(vec![1], drop));
+///     Some(())
+/// });
+/// leaked.unwrap().push(2); // the vector was dropped on exit
 /// ```
-/// use scoped::{Guard, scoped};
+/// Methods that run or drop scheduled items while the scope is still going,
+/// like [`Guard::cancel`] and [`Guard::run_exit_now`], take `&mut self` for the same reason.
 ///
-/// fn main() {
-///     use std::cell::Cell;
+/// For the same reason, a callback can't hold on to the item of another callback,
+/// which would alias the `&mut T` that callback is handed once it runs:
+/// ```compile_fail
+/// use scoped::scoped;
 ///
-///     let mut number = Cell::new(0);
+/// scoped(|guard| {
+///     let log = guard.on_scope_exit(vec![], |log: Vec<&str>| println!("{:?}", log));
+///     guard.on_scope_exit_fn(move || log.push("closing")); // runs first, but borrows the guard
+///     Some(())
+/// });
+/// ```
+/// To share state between callbacks, schedule a shared handle instead, like a `&'a RefCell<T>` or an `Rc<RefCell<T>>`.
+/// Since the callbacks of a phase run last scheduled first, a callback sees the effects of the callbacks scheduled after it,
+/// and the borrow each callback takes ends before the next one runs:
+/// ```
+/// use scoped::scoped;
+/// use std::cell::RefCell;
 ///
-///     scoped(|guard| -> Result<(), ()> {     
-///         
-///         guard.on_scope_exit(&number, move |n| {
-///             assert_eq!(n.get(), 2);
-///             n.set(3);
-///         });
+/// let log = RefCell::new(vec![]);
+/// scoped(|guard| {
+///     guard.on_scope_exit(&log, |log| assert_eq!(*log.borrow(), ["closing"]));
+///     guard.on_scope_exit(&log, |log| log.borrow_mut().push("closing"));
+///     Some(())
+/// });
+/// ```
 ///
-///         guard.on_scope_success(&number, move |n| {
-///             assert_eq!(n.get(), 1);
-///             n.set(2);
-///         });
+/// Items can be references to trait objects, like a `&mut dyn Write`,
+/// which lets a cleanup work on any writer without being generic over it.
+/// The referent just has to outlive `'a`, like any other item.
 ///
-///         number.set(1);
-///         Ok(())
+/// # Dropping callbacks without running them
+/// A callback that never runs, because it was cancelled, cleared, or scheduled for the branch the scope did not take,
+/// is dropped along with its item: the item first, then the closure,
+/// so whatever the closure captured is still around while the item drops.
+/// Several such callbacks are dropped in the order they were scheduled.
+///
+/// # Scheduling from callbacks
+/// Callbacks must outlive `'a`, which the guard itself does not.
+/// A callback therefore can't capture the guard, and thus can't schedule more callbacks while the callbacks run:
+/// ```compile_fail
+/// use scoped::scoped;
+///
+/// scoped(|guard| {
+///     guard.on_scope_exit_fn(|| {
+///         guard.on_scope_exit_fn(|| println!("follow-up"));
 ///     });
-///     assert_eq!(number.get(), 3);
-/// }
+///     Some(())
+/// });
 /// ```
-pub fn scoped<'a, R: Failure>(scope: impl FnOnce(&mut Guard<'a>) -> R) -> R {
-    let mut guard = Guard::default();
+/// A cleanup that needs a follow-up should perform it itself, at the end of its own closure.
+pub struct Guard<'a> {
+    /// Callbacks to be run on a scope's success.
+    on_scope_success: Deferring<'a>,
 
-    let ret = scope(&mut guard);
+    /// Callbacks to be run on a scope's failure.
+    on_scope_failure: Deferring<'a>,
 
-    if !ret.is_error() {
-        guard.on_scope_success.execute();
-    } else {
-        guard.on_scope_failure.execute();
-    }
+    /// Callbacks to be run on a scope's success or failure, after the respective callbacks.
+    on_scope_finish: Deferring<'a>,
 
-    guard.on_scope_exit.execute();
+    /// Callbacks to be run on a scope's exit.
+    on_scope_exit: Deferring<'a>,
 
-    ret
-}
+    /// The maximum number of callbacks pending at once, see [`Guard::with_limit`].
+    limit: Option<usize>,
 
-pub type ScopeResult<E> = Result<(), E>;
+    /// The values returned by callbacks scheduled with [`Guard::on_scope_exit_map`],
+    /// shared with those callbacks. Only allocated once such a callback is scheduled.
+    collected: OnceCell<Collected>,
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The error returned by the scope, see [`Guard::on_scope_failure_with_error`].
+    /// Only allocated once such a callback is scheduled.
+    error: OnceCell<ResultSlot>,
 
-    #[test]
-    fn test_list() {
-        let mut v = vec![1, 2, 3, 4, 5];
-        let scope = scoped(|guard| {
-            let v = guard.on_scope_success(&mut v, |v| {
-                println!("SUCCES!");
+    /// The value returned by the scope, see [`Guard::on_scope_exit_with_value`].
+    /// Only allocated once such a callback is scheduled.
+    value: OnceCell<ResultSlot>,
 
-                assert_eq!(*v, vec![1, 2, 3, 4, 5, 6, 7]);
+    /// The number of success and failure callbacks that ran, see [`Guard::on_scope_exit_with_counts`].
+    /// Only allocated once such a callback is scheduled.
+    counts: OnceCell<Rc<Cell<PhaseCounts>>>,
 
-                v.push(10);
-            });
+    /// When the guard was created, see [`Guard::on_scope_exit_if_elapsed`].
+    #[cfg(feature = "std")]
+    created: std::time::Instant,
 
-            let boxed = guard.on_scope_exit(Box::new(1), move |boxed| {
-                assert_eq!(*boxed, 12);
-            });
+    /// Whether the scope marked itself as failing, see [`Guard::mark_failing`].
+    failing: Cell<bool>,
+}
 
-            v.push(6);
-            v.push(7);
+/// The values collected from the callbacks of a guard, see [`Guard::on_scope_exit_map`].
+type Collected = Rc<RefCell<Vec<Box<dyn Any>>>>;
 
-            **boxed = 12;
+/// Points to the error or the value returned by the scope,
+/// while [`scoped_result`] or [`scoped_value`] runs the callbacks.
+type ResultSlot = Rc<Cell<Option<NonNull<dyn Any>>>>;
 
-            Some(10)
-        });
+impl<'a> Default for Guard<'a> {
+    fn default() -> Self {
+        Self::with_capacity(0, 0, 0)
     }
+}
 
-    #[test]
-    fn main_test() {
-        use std::cell::Cell;
-
-        let number = Cell::new(0);
+impl<'a> Guard<'a> {
+    /// Creates a new guard, with room for `success`, `failure` and `exit` callbacks
+    /// to be scheduled on the respective phases before reallocating.
+    pub fn with_capacity(success: usize, failure: usize, exit: usize) -> Self {
+        let guard = Self {
+            on_scope_success: Deferring::with_capacity(success),
+            on_scope_failure: Deferring::with_capacity(failure),
+            on_scope_finish: Deferring::default(),
+            on_scope_exit: Deferring::with_capacity(exit),
+            limit: None,
+            collected: OnceCell::new(),
+            error: OnceCell::new(),
+            value: OnceCell::new(),
+            counts: OnceCell::new(),
+            #[cfg(feature = "std")]
+            created: std::time::Instant::now(),
+            failing: Cell::new(false),
+        };
 
-        let n = scoped(|guard| {
-            let n = Some(1);
+        #[cfg(feature = "std")]
+        guard.catch_panics(When::Exit, true);
+        guard
+    }
 
-            guard.on_scope_success(&number, move |b| {
-                assert!(2 == b.get());
-                b.set(0);
-            });
+    /// Sets whether a panic in one of the callbacks of the phase `when` is caught,
+    /// so the remaining callbacks of that phase still run. Once they ran, the first caught panic is resumed.
+    ///
+    /// This is enabled for the exit callbacks by default, as they are meant to always run,
+    /// and disabled for the other phases, where a panic skips the remaining callbacks of the phase.
+    ///
+    /// Panics are only caught while the scope returns normally.
+    /// A callback that panics while the scope is already unwinding still aborts the process.
+    #[cfg(feature = "std")]
+    pub fn catch_panics(&self, when: When, catch: bool) {
+        self.deferring(when).catch_panics.set(catch);
+    }
 
-            guard.on_scope_failure(&number, move |b| {
-                b.set(-1);
-            });
+    /// Creates a new guard on which at most `max` callbacks, summed over all phases, can be pending at once.
+    ///
+    /// This is a safety valve against runaway scheduling, for example by a recursive routine.
+    /// Scheduling beyond the limit panics, use [`Guard::try_on_scope_exit`] to handle it instead.
+    /// Callbacks that already ran or were cancelled don't count towards the limit.
+    pub fn with_limit(max: usize) -> Self {
+        Self {
+            limit: Some(max),
+            ..Self::default()
+        }
+    }
+
+    /// Returns true if `additional` more callbacks can be scheduled without exceeding the limit.
+    fn has_room_for(&self, additional: usize) -> bool {
+        match self.limit {
+            Some(max) => self.pending() + additional <= max,
+            None => true,
+        }
+    }
+
+    /// Returns the queue of the phase `when`, to schedule a callback on.
+    ///
+    /// # Panics
+    /// Panics if the guard has no room for another callback.
+    fn scheduling(&self, when: When) -> &Deferring<'a> {
+        if !self.has_room_for(1) {
+            self.limit_exceeded();
+        }
+        self.deferring(when)
+    }
+
+    #[cold]
+    fn limit_exceeded(&self) -> ! {
+        panic!(
+            "scoped: exceeded the limit of {} scheduled callbacks",
+            self.limit.unwrap_or_default()
+        )
+    }
+
+    fn pending(&self) -> usize {
+        self.pending_success()
+            + self.pending_failure()
+            + self.on_scope_finish.len()
+            + self.pending_exit()
+    }
+
+    fn deferring(&self, when: When) -> &Deferring<'a> {
+        match when {
+            When::Success => &self.on_scope_success,
+            When::Failure => &self.on_scope_failure,
+            When::Finish => &self.on_scope_finish,
+            When::Exit => &self.on_scope_exit,
+        }
+    }
+
+    fn deferring_mut(&mut self, when: When) -> &mut Deferring<'a> {
+        match when {
+            When::Success => &mut self.on_scope_success,
+            When::Failure => &mut self.on_scope_failure,
+            When::Finish => &mut self.on_scope_finish,
+            When::Exit => &mut self.on_scope_exit,
+        }
+    }
+
+    /// Runs the callbacks of the phase `when`, returning how many ran.
+    fn execute(&mut self, when: When, outcome: Outcome) -> usize {
+        self.deferring_mut(when).run(outcome, Some(when))
+    }
+
+    /// Schedules defered closure `dc` to run in the phase picked by `when`.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::{scoped, Guard, When};
+    ///
+    /// fn log_on<'a>(guard: &Guard<'a>, when: When, msg: &'static str) {
+    ///     guard.on_scope(when, msg, |msg| println!("{}", msg));
+    /// }
+    ///
+    /// scoped(|guard| {
+    ///     log_on(guard, When::Success, "succeeded");
+    ///     log_on(guard, When::Exit, "exited");
+    ///     Some(())
+    /// });
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope<T: 'a>(&self, when: When, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.scheduling(when).push(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's success.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.on_scope(When::Success, item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.on_scope(When::Exit, item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.on_scope(When::Failure, item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's success, for an owned item and a closure that borrows nothing.
+    ///
+    /// This is [`Guard::on_scope_success`] restricted to `'static` items and closures,
+    /// which spares generic code from naming the guard's lifetime. It shares the machinery of the other scheduling methods,
+    /// the returned reference still borrows the guard.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success_owned<T: 'static>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'static,
+    ) -> &mut T {
+        self.on_scope_success(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, for an owned item and a closure that borrows nothing.
+    ///
+    /// See [`Guard::on_scope_success_owned`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_owned<T: 'static>(&self, item: T, dc: impl FnOnce(T) + 'static) -> &mut T {
+        self.on_scope_exit(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure, for an owned item and a closure that borrows nothing.
+    ///
+    /// See [`Guard::on_scope_success_owned`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure_owned<T: 'static>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'static,
+    ) -> &mut T {
+        self.on_scope_failure(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit once for every item in `items`.
+    ///
+    /// Every item gets a callback of its own, with its own clone of `dc`,
+    /// so the items are cleaned up exactly as if they were scheduled one by one:
+    /// the last item runs first, and each counts towards the [limit](Guard::with_limit).
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     let files = vec!["a.txt", "b.txt", "c.txt"];
+    ///     guard.on_scope_exit_each(files, |name| println!("closing {}", name));
+    ///     Some(())
+    /// });
+    /// ```
+    pub fn on_scope_exit_each<T: 'a, F>(&self, items: impl IntoIterator<Item = T>, dc: F)
+    where
+        F: FnOnce(T) + Clone + 'a,
+    {
+        for item in items {
+            self.on_scope_exit(item, dc.clone());
+        }
+    }
+
+    /// Schedules the shared closure `shared` to run on a scope's exit, with `item`.
+    ///
+    /// Unlike the other scheduling methods, this does not take ownership of a closure,
+    /// so one stateful closure can service many items, and be inspected again afterwards.
+    /// The closure is mutably borrowed only while it is called for an item, and released right after.
+    /// If it is borrowed elsewhere at that moment, running the callback panics.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let mut closed = 0;
+    /// let close = Rc::new(RefCell::new(|_: &str| closed += 1));
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_exit_shared("a.txt", &close);
+    ///     guard.on_scope_exit_shared("b.txt", &close);
+    ///     Some(())
+    /// });
+    ///
+    /// drop(close);
+    /// assert_eq!(closed, 2);
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_shared<T: 'a, F>(&self, item: T, shared: &Rc<RefCell<F>>) -> &mut T
+    where
+        F: FnMut(T) + ?Sized + 'a,
+    {
+        let shared = Rc::clone(shared);
+        self.on_scope_exit(item, move |item| (shared.borrow_mut())(item))
+    }
+
+    /// Like [`Guard::on_scope_exit`], but returns the item back in a [`LimitExceeded`] instead of panicking
+    /// if the guard has reached its [limit](Guard::with_limit).
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped_with_limit;
+    ///
+    /// scoped_with_limit(1, |guard| {
+    ///     assert!(guard.try_on_scope_exit("first", drop).is_ok());
+    ///
+    ///     let err = guard.try_on_scope_exit("second", drop).unwrap_err();
+    ///     assert_eq!(err.into_item(), "second");
+    ///     Some(())
+    /// });
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn try_on_scope_exit<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> Result<&mut T, LimitExceeded<T>> {
+        if self.has_room_for(1) {
+            Ok(self.on_scope_exit(item, dc))
+        } else {
+            Err(LimitExceeded { item })
+        }
+    }
+
+    /// Acquires a resource by calling `acquire` right away, and schedules `release` to run with it on a scope's exit.
+    /// Returns a mutable reference to the resource.
+    ///
+    /// This pairs setup and teardown in one place, like a value with a `Drop` impl would.
+    /// If `acquire` panics, nothing is scheduled.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    /// use std::cell::Cell;
+    ///
+    /// let connections = Cell::new(0);
+    ///
+    /// scoped(|guard| {
+    ///     let conn = guard.with_resource(
+    ///         || { connections.set(connections.get() + 1); "conn-1" },
+    ///         |_| connections.set(connections.get() - 1),
+    ///     );
+    ///     assert_eq!(*conn, "conn-1");
+    ///     assert_eq!(connections.get(), 1);
+    ///     Some(())
+    /// });
+    ///
+    /// assert_eq!(connections.get(), 0);
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn with_resource<T: 'a>(
+        &self,
+        acquire: impl FnOnce() -> T,
+        release: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        self.on_scope_exit(acquire(), release)
+    }
+
+    /// Schedules `on_ok` or `on_err` to run on a scope's exit, depending on `result`.
+    ///
+    /// Which closure runs is decided by `result` alone, not by the outcome of the scope:
+    /// `on_ok` receives the value of an `Ok`, `on_err` the error of an `Err`, even if the scope failed or succeeded.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     let lock = std::fs::File::create("/this/does/not/exist.lock");
+    ///     guard.on_result(
+    ///         lock,
+    ///         |_| println!("removing the lock file"),
+    ///         |e| println!("no lock file to remove: {}", e),
+    ///     );
+    ///     Some(())
+    /// });
+    /// ```
+    pub fn on_result<T: 'a, E: 'a>(
+        &self,
+        result: Result<T, E>,
+        on_ok: impl FnOnce(T) + 'a,
+        on_err: impl FnOnce(E) + 'a,
+    ) {
+        self.scheduling(When::Exit).push_boxed(Box::new(OnResult {
+            result,
+            on_ok,
+            on_err,
+        }));
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, with a `priority`.
+    ///
+    /// Exit callbacks with a higher priority run before the ones with a lower priority,
+    /// regardless of the order they were scheduled in. Callbacks scheduled without a priority have priority `0`.
+    /// Callbacks of the same priority run in the [exit order](Guard::exit_order), last scheduled first by default.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    /// use std::cell::RefCell;
+    ///
+    /// let log = RefCell::new(vec![]);
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_exit_priority(10, &log, |l| l.borrow_mut().push("flush buffers"));
+    ///     guard.on_scope_exit(&log, |l| l.borrow_mut().push("close file"));
+    ///     Some(())
+    /// });
+    ///
+    /// assert_eq!(*log.borrow(), vec!["flush buffers", "close file"]);
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_priority<T: 'a>(
+        &self,
+        priority: i32,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        let deferring = self.scheduling(When::Exit);
+        let (id, item) = deferring.schedule(item, dc);
+        deferring.prioritize(id, priority);
+        item
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, but only if `cond` returns true.
+    /// `cond` is evaluated when the exit callbacks run, not when scheduling.
+    /// If it returns false, the item is dropped without calling `dc`.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_if<T: 'a>(
+        &self,
+        item: T,
+        cond: impl FnOnce() -> bool + 'a,
+        dc: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        self.on_scope_exit(item, move |item| {
+            if cond() {
+                dc(item)
+            }
+        })
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, but only if more than `limit` has passed
+    /// between the creation of the guard and the time the exit callbacks run.
+    /// Otherwise the item is dropped without calling `dc`.
+    ///
+    /// This does not interrupt a scope that takes too long, it only lets a cleanup react to it afterwards,
+    /// like reporting a slow operation.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    /// use std::time::Duration;
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_exit_if_elapsed("import", Duration::from_secs(10), |op| {
+    ///         eprintln!("{} took more than 10 seconds", op);
+    ///     });
+    ///     Some(())
+    /// });
+    /// ```
+    #[cfg(feature = "std")]
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_if_elapsed<T: 'a>(
+        &self,
+        item: T,
+        limit: std::time::Duration,
+        dc: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        let created = self.created;
+        self.on_scope_exit_if(item, move || created.elapsed() > limit, dc)
+    }
+
+    /// Schedules defered closure `dc`, which takes no item, to run on a scope's success.
+    pub fn on_scope_success_fn(&self, dc: impl FnOnce() + 'a) {
+        self.scheduling(When::Success).push((), move |()| dc());
+    }
+
+    /// Schedules defered closure `dc`, which takes no item, to run on a scope's exit.
+    pub fn on_scope_exit_fn(&self, dc: impl FnOnce() + 'a) {
+        self.scheduling(When::Exit).push((), move |()| dc());
+    }
+
+    /// Schedules defered closure `dc`, which takes no item, to run on a scope's failure.
+    pub fn on_scope_failure_fn(&self, dc: impl FnOnce() + 'a) {
+        self.scheduling(When::Failure).push((), move |()| dc());
+    }
+
+    /// Like [`Guard::on_scope_success`], but also returns a [`CallbackId`] to cancel the callback with.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success_with_id<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> (CallbackId, &mut T) {
+        let (id, item) = self.scheduling(When::Success).schedule(item, dc);
+        (
+            CallbackId {
+                phase: When::Success,
+                id,
+            },
+            item,
+        )
+    }
+
+    /// Like [`Guard::on_scope_exit`], but also returns a [`CallbackId`] to cancel the callback with.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_with_id<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> (CallbackId, &mut T) {
+        let (id, item) = self.scheduling(When::Exit).schedule(item, dc);
+        (
+            CallbackId {
+                phase: When::Exit,
+                id,
+            },
+            item,
+        )
+    }
+
+    /// Like [`Guard::on_scope_failure`], but also returns a [`CallbackId`] to cancel the callback with.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure_with_id<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> (CallbackId, &mut T) {
+        let (id, item) = self.scheduling(When::Failure).schedule(item, dc);
+        (
+            CallbackId {
+                phase: When::Failure,
+                id,
+            },
+            item,
+        )
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's success,
+    /// returning a handle to [`peek`](Guard::peek) at the item instead of a mutable reference to it.
+    pub fn on_scope_success_peekable<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> ItemId<T> {
+        let (id, _) = self.scheduling(When::Success).schedule(item, dc);
+        Self::item_id(When::Success, id)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit,
+    /// returning a handle to [`peek`](Guard::peek) at the item instead of a mutable reference to it.
+    pub fn on_scope_exit_peekable<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> ItemId<T> {
+        let (id, _) = self.scheduling(When::Exit).schedule(item, dc);
+        Self::item_id(When::Exit, id)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure,
+    /// returning a handle to [`peek`](Guard::peek) at the item instead of a mutable reference to it.
+    pub fn on_scope_failure_peekable<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> ItemId<T> {
+        let (id, _) = self.scheduling(When::Failure).schedule(item, dc);
+        Self::item_id(When::Failure, id)
+    }
+
+    fn item_id<T>(phase: When, id: usize) -> ItemId<T> {
+        ItemId {
+            id: CallbackId { phase, id },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns a shared reference to the item of the callback identified by `id`,
+    /// or `None` if the callback is no longer pending, or was scheduled on another guard.
+    ///
+    /// # Aliasing
+    /// The `_peekable` methods never hand out a mutable reference to the item,
+    /// so any number of shared references returned by this method may be alive at once.
+    /// Everything that touches the item again, like [`Guard::cancel`] and [`Guard::run_exit_now`],
+    /// takes `&mut self`, which ends all of them before the item is moved or dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     let id = guard.on_scope_exit_peekable(vec![1, 2, 3], |v| drop(v));
+    ///
+    ///     let a = guard.peek(id).unwrap();
+    ///     let b = guard.peek(id).unwrap();
+    ///     assert_eq!(a, b);
+    ///
+    ///     Some(())
+    /// });
+    /// ```
+    pub fn peek<T>(&self, id: ItemId<T>) -> Option<&T> {
+        let item = self.deferring(id.id.phase).item(id.id.id)?;
+
+        // Ids are unique across guards, so this entry was created together with `id`,
+        // meaning its item really is a `T`. Only shared references to it exist, see above.
+        Some(unsafe { item.cast::<T>().as_ref() })
+    }
+
+    /// Replaces the item of the callback identified by `id` with `new`, returning the old item.
+    /// The callback then receives `new` when it runs.
+    ///
+    /// Returns `None`, dropping `new`, if the callback already ran or was cancelled.
+    ///
+    /// This takes `&mut self`, because the old item may still be borrowed through [`Guard::peek`].
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     let id = guard.on_scope_exit_peekable("tmp/part-1", |path| assert_eq!(path, "tmp/part-2"));
+    ///     assert_eq!(guard.replace_item(id, "tmp/part-2"), Some("tmp/part-1"));
+    ///     Some(())
+    /// });
+    /// ```
+    ///
+    /// The new item has to live as long as the old one, as the callback may run after it is gone otherwise:
+    /// ```compile_fail
+    /// use scoped::scoped;
+    ///
+    /// let long = String::from("long");
+    /// scoped(|guard| {
+    ///     let id = guard.on_scope_exit_peekable(&long, |s| println!("{}", s));
+    ///     let short = String::from("short");
+    ///     guard.replace_item(id, &short);
+    ///     drop(short);
+    ///     Some(())
+    /// });
+    /// ```
+    pub fn replace_item<T>(&mut self, id: ItemId<T>, new: T) -> Option<T> {
+        let item = self.deferring_mut(id.id.phase).item(id.id.id)?;
+
+        // See `Guard::peek`, the entry's item is a `T`.
+        // No reference into it is alive, as this takes `&mut self`.
+        Some(core::mem::replace(
+            unsafe { &mut *item.cast::<T>().as_ptr() },
+            new,
+        ))
+    }
+
+    /// Cancels the callback identified by `id`, so it won't run.
+    /// The callback and its item are dropped right away.
+    ///
+    /// Returns true if the callback was still pending, false if it was cancelled before.
+    ///
+    /// This takes `&mut self`, because cancelling drops the item
+    /// that a reference returned by the scheduling methods may still point to.
+    pub fn cancel(&mut self, id: CallbackId) -> bool {
+        self.deferring_mut(id.phase).cancel(id.id)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, replacing the pending exit callback
+    /// scheduled under the same `key`, if any.
+    ///
+    /// Last write wins: the replaced callback is dropped along with its item right away, without running,
+    /// and the new callback takes its turn as if the old one was never scheduled.
+    /// A key is free again once its callback ran or was cancelled.
+    /// Callbacks moved over by [`Guard::absorb`] keep their keys, so they can be replaced on the absorbing guard.
+    ///
+    /// This takes `&mut self`, like [`Guard::cancel`], because replacing a callback drops its item.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    /// use std::cell::RefCell;
+    ///
+    /// let closed = RefCell::new(vec![]);
+    ///
+    /// scoped(|guard| {
+    ///     for (conn, attempt) in [("db", 1), ("cache", 1), ("db", 2)] {
+    ///         guard.on_scope_exit_keyed(conn, (conn, attempt), |c| closed.borrow_mut().push(c));
+    ///     }
+    ///     Some(())
+    /// });
+    ///
+    /// assert_eq!(*closed.borrow(), vec![("db", 2), ("cache", 1)]);
+    /// ```
+    pub fn on_scope_exit_keyed<K: Eq + 'static, T: 'a>(
+        &mut self,
+        key: K,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        self.on_scope_exit.cancel_keyed(&key);
+
+        let deferring = self.scheduling(When::Exit);
+        let (id, item) = deferring.schedule(item, dc);
+        deferring.set_key(id, Box::new(key));
+        item
+    }
+
+    /// Moves all callbacks scheduled on `other` to the respective phases of `self`.
+    ///
+    /// The absorbed callbacks are appended after the ones already scheduled on `self`,
+    /// as if they were scheduled on `self` right now, keeping their relative order.
+    /// Under the default [`Order::Lifo`] they thus run before the callbacks `self` already had.
+    /// The exit order configured on `other` does not carry over.
+    ///
+    /// [`CallbackId`]s and [`ItemId`]s handed out by `other` remain valid, and now refer to `self`.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::{scoped, Guard};
+    ///
+    /// fn helper<'a>() -> Guard<'a> {
+    ///     let guard = Guard::default();
+    ///     guard.on_scope_exit_fn(|| println!("helper cleanup"));
+    ///     guard
+    /// }
+    ///
+    /// scoped(|guard| {
+    ///     guard.absorb(helper());
+    ///     assert_eq!(guard.pending_exit(), 1);
+    ///     Some(())
+    /// });
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if absorbing the callbacks would exceed the [limit](Guard::with_limit) of `self`.
+    pub fn absorb(&self, other: Guard<'a>) {
+        if !self.has_room_for(other.pending()) {
+            self.limit_exceeded();
+        }
+        self.on_scope_success.append(other.on_scope_success);
+        self.on_scope_failure.append(other.on_scope_failure);
+        self.on_scope_finish.append(other.on_scope_finish);
+        self.on_scope_exit.append(other.on_scope_exit);
+    }
+
+    /// Discards all scheduled callbacks, in every phase.
+    ///
+    /// This does *not* run the callbacks, it just drops them along with their items.
+    /// Like [`Guard::cancel`], this takes `&mut self` because the items are dropped.
+    pub fn clear(&mut self) {
+        self.clear_success();
+        self.clear_failure();
+        self.on_scope_finish.clear();
+        self.clear_exit();
+    }
+
+    /// Discards the callbacks scheduled to run on a scope's success, without running them.
+    pub fn clear_success(&mut self) {
+        self.on_scope_success.clear();
+    }
+
+    /// Discards the callbacks scheduled to run on a scope's failure, without running them.
+    pub fn clear_failure(&mut self) {
+        self.on_scope_failure.clear();
+    }
+
+    /// Discards the callbacks scheduled to run on a scope's exit, without running them.
+    pub fn clear_exit(&mut self) {
+        self.on_scope_exit.clear();
+    }
+
+    /// Sets the order in which the exit callbacks run, which is [`Order::Lifo`] by default.
+    ///
+    /// This only affects the exit callbacks, success and failure callbacks always run last in, first out.
+    /// The order applies to all exit callbacks, including the ones scheduled before calling this.
+    pub fn exit_order(&self, order: Order) {
+        self.on_scope_exit.order.set(order);
+    }
+
+    /// Runs the exit callbacks scheduled so far right away, as a checkpoint, instead of at the end of the scope.
+    /// Exit callbacks scheduled after this accumulate afresh, and run when the scope exits (or at the next checkpoint).
+    ///
+    /// The callbacks run in the configured [`exit_order`](Guard::exit_order).
+    /// Callbacks scheduled with [`Guard::on_scope_exit_with_outcome`] receive [`Outcome::Success`],
+    /// as the scope made it to the checkpoint.
+    ///
+    /// This takes `&mut self`, because running a callback consumes its item,
+    /// which a reference returned by the scheduling methods may still point to.
+    /// For the same reason a callback can't reach the guard, so it can't schedule new callbacks during the flush.
+    pub fn run_exit_now(&mut self) {
+        self.set_counts(PhaseCounts::default());
+        self.execute(When::Exit, Outcome::Success);
+    }
+
+    /// Marks the scope as failing, so it takes the failure path no matter what it returns.
+    ///
+    /// The mark is ORed with the [`Failure`] impl of the returned value: a scope fails if it is marked,
+    /// or if it returns a failure. There is no way to unmark a scope,
+    /// and a marked scope can't succeed by returning a success.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    /// use std::cell::Cell;
+    ///
+    /// let rolled_back = Cell::new(false);
+    /// let ret = scoped(|guard| {
+    ///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+    ///     guard.mark_failing();
+    ///     if !guard.is_marked_failing() {
+    ///         // expensive setup that only matters on success
+    ///     }
+    ///     Some(())
+    /// });
+    ///
+    /// assert_eq!(ret, Some(()));
+    /// assert!(rolled_back.get());
+    /// ```
+    pub fn mark_failing(&self) {
+        self.failing.set(true);
+    }
+
+    /// Returns true if the scope was marked as failing with [`Guard::mark_failing`].
+    pub fn is_marked_failing(&self) -> bool {
+        self.failing.get()
+    }
+
+    /// Returns the outcome of the scope, given the `outcome` of the value it returned.
+    fn decide(&self, outcome: Outcome) -> Outcome {
+        if self.failing.get() {
+            Outcome::Failure
+        } else {
+            outcome
+        }
+    }
+
+    /// Returns the number of callbacks currently scheduled to run on a scope's success.
+    pub fn pending_success(&self) -> usize {
+        self.on_scope_success.len()
+    }
+
+    /// Returns the number of callbacks currently scheduled to run on a scope's failure.
+    pub fn pending_failure(&self) -> usize {
+        self.on_scope_failure.len()
+    }
+
+    /// Returns the number of callbacks currently scheduled to run on a scope's exit.
+    pub fn pending_exit(&self) -> usize {
+        self.on_scope_exit.len()
+    }
+
+    /// Like [`Guard::on_scope_success`], but the item shows up in [`Guard::debug_pending`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success_debug<T: Debug + 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        self.scheduling(When::Success).schedule_debug(item, dc)
+    }
+
+    /// Like [`Guard::on_scope_exit`], but the item shows up in [`Guard::debug_pending`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_debug<T: Debug + 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.scheduling(When::Exit).schedule_debug(item, dc)
+    }
+
+    /// Like [`Guard::on_scope_failure`], but the item shows up in [`Guard::debug_pending`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure_debug<T: Debug + 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + 'a,
+    ) -> &mut T {
+        self.scheduling(When::Failure).schedule_debug(item, dc)
+    }
+
+    /// Lists the callbacks currently scheduled on this guard, for debugging cleanups.
+    ///
+    /// Items are type erased once scheduled, so the guard can only format the items it was told how to format:
+    /// the ones scheduled with [`Guard::on_scope_success_debug`], [`Guard::on_scope_failure_debug`]
+    /// and [`Guard::on_scope_exit_debug`]. All other callbacks are still listed, but without their item.
+    ///
+    /// This takes `&mut self`, because formatting reads the items,
+    /// which a reference returned by the scheduling methods may still point to.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::{scoped, When};
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_exit_debug(vec![1, 2], drop);
+    ///     guard.on_scope_failure_fn(|| println!("rolling back"));
+    ///
+    ///     let report = guard.debug_pending();
+    ///     assert_eq!(report.items.len(), 2);
+    ///     assert_eq!(report.items[0].when, When::Failure);
+    ///     assert_eq!(report.items[0].item, None);
+    ///     assert_eq!(report.items[1].item.as_deref(), Some("[1, 2]"));
+    ///
+    ///     Some(())
+    /// });
+    /// ```
+    pub fn debug_pending(&mut self) -> PendingReport {
+        let mut items = Vec::new();
+        for when in [When::Success, When::Failure, When::Finish, When::Exit] {
+            items.extend(
+                self.deferring_mut(when)
+                    .debug_items()
+                    .map(|item| PendingItem { when, item }),
+            );
+        }
+        PendingReport { items }
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit.
+    /// Next to the item, `dc` receives the [`Outcome`] of the scope.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_with_outcome<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T, Outcome) + 'a,
+    ) -> &mut T {
+        self.scheduling(When::Exit)
+            .schedule_with_outcome(item, dc)
+            .1
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, borrowing the item instead of taking it.
+    ///
+    /// The item stays with the callback while `dc` runs, and is dropped right after it returned.
+    /// This suits items whose `Drop` is the actual cleanup, with `dc` preparing it.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     let buf = guard.on_scope_exit_ref(Vec::new(), |buf| buf.push(b'\n'));
+    ///     buf.extend_from_slice(b"done");
+    ///     Some(())
+    /// });
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_ref<T: 'a>(&self, item: T, dc: impl FnOnce(&mut T) + 'a) -> &mut T {
+        self.on_scope_exit(item, move |mut item| dc(&mut item))
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, borrowing the item, which is then kept.
+    ///
+    /// Like [`Guard::on_scope_exit_ref`], except that the item is collected once `dc` returned,
+    /// as if by [`Guard::on_scope_exit_map`], so [`scoped_collect`] hands it back.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped_collect;
+    ///
+    /// let (_, collected) = scoped_collect(|guard| {
+    ///     guard.on_scope_exit_ref_keep(vec![3, 1, 2], |v| v.sort());
+    ///     Some(())
+    /// });
+    ///
+    /// assert_eq!(collected[0].downcast_ref::<Vec<i32>>(), Some(&vec![1, 2, 3]));
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_ref_keep<T: 'static>(
+        &self,
+        item: T,
+        dc: impl FnOnce(&mut T) + 'a,
+    ) -> &mut T {
+        self.on_scope_exit_map(item, move |mut item| {
+            dc(&mut item);
+            item
+        })
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit.
+    /// Next to the item, `dc` receives the number of success and failure callbacks that ran before it.
+    ///
+    /// Callbacks [run early](Guard::run_exit_now), or [absorbed](Guard::absorb) into another guard, receive zero counts.
+    /// The exit callbacks of a failed attempt of [`scoped_retry`] receive the counts of that attempt.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_exit_with_counts("import", |what, counts| {
+    ///         println!("{}: {} rollbacks", what, counts.failure_ran);
+    ///     });
+    ///     guard.on_scope_failure_fn(|| println!("removing the imported rows"));
+    ///     None::<()>
+    /// });
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_with_counts<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T, PhaseCounts) + 'a,
+    ) -> &mut T {
+        let counts = Rc::clone(self.counts.get_or_init(Rc::default));
+        self.on_scope_exit(item, move |item| dc(item, counts.get()))
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, collecting the value it returns.
+    ///
+    /// The collected values are handed out by [`scoped_collect`], boxed as [`Any`],
+    /// in the order the callbacks ran. Other drivers, like [`scoped`](fn@scoped), drop them along with the guard.
+    /// Values returned by callbacks that were [absorbed](Guard::absorb) from another guard are not collected.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_map<T: 'a, U: 'static>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) -> U + 'a,
+    ) -> &mut T {
+        let collected = Rc::clone(self.collected.get_or_init(Rc::default));
+        self.on_scope_exit(item, move |item| {
+            let value = dc(item);
+            collected.borrow_mut().push(Box::new(value));
+        })
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure.
+    /// Next to the item, `dc` receives the error the scope returned.
+    ///
+    /// The error is only known to [`scoped_result`], which hands it out as `Some` if it is an `E`.
+    /// `dc` receives `None` if the scope panicked, or was driven by any other function.
+    ///
+    /// The error is borrowed, not cloned: it stays in the `Err` the scope returned,
+    /// which is handed back to the caller once the callbacks ran. So the reference can't outlive `dc`,
+    /// clone the error to keep it around.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped_result;
+    /// use std::io;
+    ///
+    /// let ret = scoped_result(|guard| {
+    ///     guard.on_scope_failure_with_error("config.toml", |path, error: Option<&io::Error>| {
+    ///         if let Some(error) = error {
+    ///             eprintln!("reading {} failed: {:?}", path, error.raw_os_error());
+    ///         }
+    ///     });
+    ///     std::fs::read_to_string("/this/does/not/exist")
+    /// });
+    ///
+    /// assert!(ret.is_err());
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure_with_error<T: 'a, E: 'static>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T, Option<&E>) + 'a,
+    ) -> &mut T {
+        let slot = Rc::clone(self.error.get_or_init(Rc::default));
+        self.on_scope_failure(item, move |item| {
+            // The slot only points to an error while `scoped_result` runs the failure callbacks,
+            // during which it keeps the error alive and does not touch it.
+            let error = slot.get().map(|error| unsafe { error.as_ref() });
+            dc(item, error.and_then(|error| error.downcast_ref()))
+        })
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, with a borrow of the value the scope returned.
+    ///
+    /// `dc` receives `Some` value if the scope succeeded and was driven by [`scoped_value`], and `None` otherwise,
+    /// that is if the scope failed, panicked or was driven by any other function, or if `T` is not the type of the value.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped_value;
+    ///
+    /// let ret = scoped_value(|guard| {
+    ///     guard.on_scope_exit_with_value("session", |what, id: Option<&u32>| match id {
+    ///         Some(id) => println!("opened {} {}", what, id),
+    ///         None => println!("opening a {} failed", what),
+    ///     });
+    ///     Ok::<_, ()>(7)
+    /// });
+    ///
+    /// assert_eq!(ret, Ok(7));
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit_with_value<U: 'a, T: 'static>(
+        &self,
+        item: U,
+        dc: impl FnOnce(U, Option<&T>) + 'a,
+    ) -> &mut U {
+        let slot = Rc::clone(self.value.get_or_init(Rc::default));
+        self.on_scope_exit(item, move |item| {
+            // See `on_scope_failure_with_error`, `scoped_value` keeps the value alive the same way.
+            let value = slot.get().map(|value| unsafe { value.as_ref() });
+            dc(item, value.and_then(|value| value.downcast_ref()))
+        })
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure, with a borrow of the error the scope returned.
+    ///
+    /// This is [`Guard::on_scope_failure_with_error`] for callbacks that only make sense with the error at hand:
+    /// if the error is unknown, because the scope panicked or was not driven by [`scoped_result`],
+    /// `dc` does not run and the item is dropped. Like any failure callback, `dc` does not run if the scope succeeded.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped_result;
+    /// use std::cell::RefCell;
+    ///
+    /// let log = RefCell::new(vec![]);
+    /// let ret = scoped_result(|guard| {
+    ///     guard.on_scope_failure_err(&log, |log, error: &String| log.borrow_mut().push(error.clone()));
+    ///     Err::<(), _>("disk full".to_string())
+    /// });
+    ///
+    /// assert_eq!(ret, Err("disk full".to_string()));
+    /// assert_eq!(*log.borrow(), vec!["disk full"]);
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure_err<U: 'a, E: 'static>(
+        &self,
+        item: U,
+        dc: impl FnOnce(U, &E) + 'a,
+    ) -> &mut U {
+        self.on_scope_failure_with_error(item, move |item, error| {
+            if let Some(error) = error {
+                dc(item, error)
+            }
+        })
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure.
+    /// Next to the item, `dc` receives the boxed error the scope returned, as its source.
+    ///
+    /// This is [`Guard::on_scope_failure_with_error`] for scopes driven by [`scoped_boxed_err`],
+    /// or by [`scoped_result`] with a `Box<dyn Error>` or `Box<dyn Error + Send + Sync>` error.
+    /// `dc` receives `None` if the scope panicked, or was driven by any other function.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped_boxed_err;
+    ///
+    /// let ret = scoped_boxed_err(|guard| {
+    ///     guard.on_scope_failure_with_source("port", |what, source| {
+    ///         eprintln!("parsing the {} failed: {}", what, source.unwrap());
+    ///     });
+    ///     let port: u16 = "eighty".parse()?;
+    ///     Ok(port)
+    /// });
+    ///
+    /// assert!(ret.is_err());
+    /// ```
+    #[cfg(feature = "std")]
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure_with_source<T: 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T, Option<&(dyn Error + 'static)>) + 'a,
+    ) -> &mut T {
+        let slot = Rc::clone(self.error.get_or_init(Rc::default));
+        self.on_scope_failure(item, move |item| {
+            // See `on_scope_failure_with_error`.
+            let error = slot.get().map(|error| unsafe { error.as_ref() });
+            let source = error.and_then(|error| match error.downcast_ref::<Box<dyn Error>>() {
+                Some(error) => Some(&**error),
+                None => error
+                    .downcast_ref::<Box<dyn Error + Send + Sync>>()
+                    .map(|error| &**error as &(dyn Error + 'static)),
+            });
+            dc(item, source)
+        })
+    }
+
+    /// Runs the callbacks as if the scope returned `code`, then exits the process with it.
+    ///
+    /// [`std::process::exit`] does not run destructors, so a scope that calls it directly skips its callbacks.
+    /// This runs them first: the success callbacks if `code` is `0`, the failure callbacks otherwise,
+    /// followed by the finish and exit callbacks. Only the callbacks of this guard run,
+    /// those of enclosing scopes and any other destructors are still skipped.
+    ///
+    /// This takes `&mut self`, so it can be called from within the scope.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_exit_fn(|| println!("flushing logs"));
+    ///     if std::env::args().count() < 2 {
+    ///         guard.exit_process(2);
+    ///     }
+    ///     Some(())
+    /// });
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn exit_process(&mut self, code: i32) -> ! {
+        self.finish(Outcome::of(&code));
+        std::process::exit(code)
+    }
+
+    /// Takes the guard apart into its success, failure, finish and exit queues, to run them with [`Deferring::execute`].
+    ///
+    /// To run them like a scope would, execute the success or the failure queue, then the finish queue,
+    /// which holds the callbacks scheduled with [`Guard::on_scope_finish`], and then the exit queue.
+    /// For [`Outcome::Indeterminate`], drop all but the exit queue.
+    /// The exit queue keeps its [order](Guard::exit_order), and the queues keep their [panic handling](Guard::catch_panics).
+    ///
+    /// Taking the guard by value ends all references returned by the scheduling methods,
+    /// so the queues are free to run or drop the items.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::{Guard, Outcome};
+    ///
+    /// let guard = Guard::default();
+    /// guard.on_scope_success_fn(|| println!("committed"));
+    /// guard.on_scope_exit_fn(|| println!("closed"));
+    ///
+    /// let (mut success, _failure, mut finish, mut exit) = guard.into_parts();
+    /// success.execute(Outcome::Success);
+    /// finish.execute(Outcome::Success);
+    /// exit.execute(Outcome::Success);
+    /// ```
+    pub fn into_parts(self) -> (Deferring<'a>, Deferring<'a>, Deferring<'a>, Deferring<'a>) {
+        let Guard {
+            on_scope_success,
+            on_scope_failure,
+            on_scope_finish,
+            on_scope_exit,
+            ..
+        } = self;
+
+        (
+            on_scope_success,
+            on_scope_failure,
+            on_scope_finish,
+            on_scope_exit,
+        )
+    }
+
+    /// Returns a copyable handle to schedule callbacks on this guard with.
+    pub fn handle(&self) -> GuardHandle<'_, 'a> {
+        GuardHandle { guard: self }
+    }
+
+    /// Turns this guard into a [`DropGuard`], which runs the exit callbacks when dropped,
+    /// without needing a scope closure.
+    pub fn into_drop_guard(self) -> DropGuard<'a> {
+        DropGuard { guard: self }
+    }
+
+    /// Schedules defered closure `dc` to run on both a scope's success and failure.
+    /// Next to the item, `dc` receives whether the scope succeeded.
+    ///
+    /// Once a scope ends, its callbacks run in this order:
+    /// 1. the success *or* the failure callbacks, depending on the outcome,
+    /// 2. the callbacks scheduled with this method,
+    /// 3. the exit callbacks.
+    ///
+    /// Unlike the exit callbacks, these are tied to the outcome, so [`Guard::run_exit_now`] does not run them.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::scoped;
+    ///
+    /// scoped(|guard| -> Result<(), ()> {
+    ///     guard.on_scope_finish("transfer", |what, succeeded| {
+    ///         if succeeded {
+    ///             println!("{} committed", what);
+    ///         } else {
+    ///             println!("{} rolled back", what);
+    ///         }
+    ///     });
+    ///     Ok(())
+    /// });
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_finish<T: 'a>(&self, item: T, dc: impl FnOnce(T, bool) + 'a) -> &mut T {
+        self.scheduling(When::Finish)
+            .schedule_with_outcome(item, move |item, outcome| {
+                dc(item, outcome == Outcome::Success)
+            })
+            .1
+    }
+
+    /// Runs the success or failure callbacks, depending on `success`, followed by the finish and exit callbacks,
+    /// just like a scope that returned does. The callbacks that did not run are discarded.
+    ///
+    /// This leaves the guard empty but keeps its allocations, ready to be reused,
+    /// for example for the next iteration of a loop.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::Guard;
+    ///
+    /// let mut guard = Guard::default();
+    /// for i in 0..3 {
+    ///     guard.on_scope_success(i, |i| println!("iteration {} succeeded", i));
+    ///     guard.on_scope_exit(i, |i| println!("iteration {} done", i));
+    ///     guard.run_phase(i % 2 == 0);
+    /// }
+    /// ```
+    pub fn run_phase(&mut self, success: bool) {
+        self.run_and_count(Outcome::failed_if(!success));
+    }
+
+    /// Runs the callbacks like [`Guard::run_phase`] does, for the given `outcome`,
+    /// returning how many callbacks of each phase ran.
+    ///
+    /// This is meant for tests, to check that every scheduled callback ran:
+    /// ```
+    /// use scoped::{Guard, Outcome};
+    ///
+    /// let mut guard = Guard::default();
+    /// guard.on_scope_failure_fn(|| println!("rolling back"));
+    /// guard.on_scope_exit_fn(|| println!("closing"));
+    ///
+    /// let counts = guard.run_and_count(Outcome::Failure);
+    /// assert_eq!((counts.failure_ran, counts.exit_ran), (1, 1));
+    /// ```
+    pub fn run_and_count(&mut self, outcome: Outcome) -> ScopeReport {
+        let report = self.finish(outcome);
+        self.on_scope_success.clear();
+        report
+    }
+
+    /// Runs the success or failure callbacks depending on `outcome`, followed by the finish and exit callbacks.
+    /// A scope [marked as failing](Guard::mark_failing) takes the failure path regardless of `outcome`.
+    fn finish(&mut self, outcome: Outcome) -> ScopeReport {
+        let outcome = self.decide(outcome);
+        let mut report = ScopeReport {
+            outcome,
+            success_ran: 0,
+            failure_ran: 0,
+            finish_ran: 0,
+            exit_ran: 0,
+        };
+
+        match outcome {
+            Outcome::Success => {
+                // Discard the failure callbacks, so they don't run if a success callback panics.
+                self.on_scope_failure.clear();
+                report.success_ran = self.execute(When::Success, outcome);
+            }
+            Outcome::Failure => report.failure_ran = self.execute(When::Failure, outcome),
+            Outcome::Indeterminate => {
+                self.on_scope_success.clear();
+                self.on_scope_failure.clear();
+                self.on_scope_finish.clear();
+            }
+        }
+
+        report.finish_ran = self.execute(When::Finish, outcome);
+        self.set_counts(PhaseCounts {
+            success_ran: report.success_ran,
+            failure_ran: report.failure_ran,
+        });
+        report.exit_ran = self.execute(When::Exit, outcome);
+        // Reset the counts, so the exit callbacks of a reused guard never see those of an earlier phase.
+        self.set_counts(PhaseCounts::default());
+        report
+    }
+
+    /// Hands `counts` to the callbacks scheduled with [`Guard::on_scope_exit_with_counts`].
+    fn set_counts(&self, counts: PhaseCounts) {
+        if let Some(slot) = self.counts.get() {
+            slot.set(counts);
+        }
+    }
+}
+
+/// The number of success and failure callbacks that ran before the exit callbacks,
+/// as handed to the callbacks scheduled with [`Guard::on_scope_exit_with_counts`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PhaseCounts {
+    /// The number of success callbacks that ran.
+    pub success_ran: usize,
+
+    /// The number of failure callbacks that ran.
+    pub failure_ran: usize,
+}
+
+/// What ran once a scope returned, as reported by [`scoped_report`] and [`Guard::run_and_count`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScopeReport {
+    /// The outcome of the scope, which decided whether the success or the failure callbacks ran.
+    pub outcome: Outcome,
+
+    /// The number of success callbacks that ran.
+    pub success_ran: usize,
+
+    /// The number of failure callbacks that ran.
+    pub failure_ran: usize,
+
+    /// The number of callbacks scheduled with [`Guard::on_scope_finish`] that ran.
+    pub finish_ran: usize,
+
+    /// The number of exit callbacks that ran at the end of the scope,
+    /// not counting the ones run earlier by [`Guard::run_exit_now`].
+    pub exit_ran: usize,
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    /// The number of scopes the current thread is in, see [`current_depth`].
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the current thread as being in one more scope, until dropped.
+///
+/// Every driver holds one while it runs the scope and its callbacks,
+/// declared before its `PanicGuard`, so it is dropped after the callbacks ran, even when unwinding.
+pub(crate) struct Depth(());
+
+impl Depth {
+    pub(crate) fn enter() -> Self {
+        #[cfg(feature = "std")]
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Depth(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Returns the number of scopes the current thread is executing, including their callbacks.
+///
+/// This is `0` outside of any scope, `1` inside of a scope, and more inside of a scope nested in another,
+/// which includes a scope run by a callback of another scope. Use it to detect unintended nesting in tests.
+///
+/// # Examples
+/// ```
+/// use scoped::{current_depth, scoped};
+///
+/// assert_eq!(current_depth(), 0);
+/// scoped(|guard| {
+///     assert_eq!(current_depth(), 1);
+///     guard.on_scope_exit_fn(|| {
+///         scoped(|_| Some(assert_eq!(current_depth(), 2)));
+///     });
+///     Some(())
+/// });
+/// assert_eq!(current_depth(), 0);
+/// ```
+#[cfg(feature = "std")]
+pub fn current_depth() -> usize {
+    DEPTH.with(Cell::get)
+}
+
+/// Runs the remaining failure, finish and exit callbacks of a guard when dropped,
+/// which matters when its scope unwinds.
+///
+/// On a normal return the driver executes the callbacks itself, leaving nothing behind,
+/// so dropping this afterwards is a no-op. This does not rely on `std::thread::panicking`,
+/// so it works without `std`.
+struct PanicGuard<'a> {
+    guard: Guard<'a>,
+}
+
+impl<'a> Drop for PanicGuard<'a> {
+    fn drop(&mut self) {
+        let failure_ran = self.guard.execute(When::Failure, Outcome::Failure);
+        self.guard.execute(When::Finish, Outcome::Failure);
+        // Keep the counts `finish` set, if it was an exit callback that panicked.
+        if failure_ran > 0 {
+            self.guard.set_counts(PhaseCounts {
+                success_ran: 0,
+                failure_ran,
+            });
+        }
+        self.guard.execute(When::Exit, Outcome::Failure);
+    }
+}
+
+/// A shared handle to a [`Guard`], created with [`Guard::handle`].
+///
+/// Handles are `Copy`, so they can be handed to any number of helpers, or stored in builders,
+/// which all schedule onto the same guard. A handle dereferences to the guard,
+/// its own scheduling methods hand out references that live as long as the handle's borrow of the guard.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, GuardHandle};
+///
+/// struct Connection<'g, 'a> {
+///     guard: GuardHandle<'g, 'a>,
+/// }
+///
+/// impl<'g, 'a> Connection<'g, 'a> {
+///     fn open(&self, name: &'static str) {
+///         self.guard.on_scope_exit(name, |name| println!("closing {}", name));
+///     }
+/// }
+///
+/// scoped(|guard| {
+///     let a = Connection { guard: guard.handle() };
+///     let b = Connection { guard: guard.handle() };
+///     a.open("a");
+///     b.open("b");
+///     assert_eq!(guard.pending_exit(), 2);
+///     Some(())
+/// });
+/// ```
+#[derive(Clone, Copy)]
+pub struct GuardHandle<'g, 'a> {
+    guard: &'g Guard<'a>,
+}
+
+impl<'g, 'a> GuardHandle<'g, 'a> {
+    /// Schedules defered closure `dc` to run on a scope's success, see [`Guard::on_scope_success`].
+    pub fn on_scope_success<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &'g mut T {
+        self.guard.on_scope_success(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit, see [`Guard::on_scope_exit`].
+    pub fn on_scope_exit<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &'g mut T {
+        self.guard.on_scope_exit(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure, see [`Guard::on_scope_failure`].
+    pub fn on_scope_failure<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &'g mut T {
+        self.guard.on_scope_failure(item, dc)
+    }
+}
+
+impl<'g, 'a> core::ops::Deref for GuardHandle<'g, 'a> {
+    type Target = Guard<'a>;
+
+    fn deref(&self) -> &Guard<'a> {
+        self.guard
+    }
+}
+
+/// A guard that runs its exit callbacks when dropped, created with [`Guard::into_drop_guard`].
+///
+/// This is plain RAII: however the enclosing block is left, be it normally, with `?`,
+/// with a labeled `break` or `continue`, or by unwinding, the exit callbacks run.
+/// There is no return value to decide on an outcome though, so the success, failure and finish callbacks
+/// are dropped without running. Callbacks scheduled with [`Guard::on_scope_exit_with_outcome`]
+/// receive [`Outcome::Failure`] if the thread is panicking, which can only be told with the `std` feature,
+/// and [`Outcome::Success`] otherwise.
+///
+/// `DropGuard` dereferences to the [`Guard`], to schedule callbacks on.
+///
+/// # Examples
+/// ```
+/// use scoped::Guard;
+/// use std::cell::Cell;
+///
+/// let closed = Cell::new(0);
+///
+/// 'files: for name in ["a.txt", "b.txt"] {
+///     let guard = Guard::default().into_drop_guard();
+///     guard.on_scope_exit(&closed, |c| c.set(c.get() + 1));
+///
+///     if name == "a.txt" {
+///         break 'files;
+///     }
+/// }
+///
+/// assert_eq!(closed.get(), 1);
+/// ```
+pub struct DropGuard<'a> {
+    guard: Guard<'a>,
+}
+
+impl<'a> core::ops::Deref for DropGuard<'a> {
+    type Target = Guard<'a>;
+
+    fn deref(&self) -> &Guard<'a> {
+        &self.guard
+    }
+}
+
+impl<'a> core::ops::DerefMut for DropGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Guard<'a> {
+        &mut self.guard
+    }
+}
+
+impl<'a> Drop for DropGuard<'a> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        let outcome = Outcome::failed_if(std::thread::panicking());
+        #[cfg(not(feature = "std"))]
+        let outcome = Outcome::Success;
+
+        self.guard.execute(When::Exit, outcome);
+    }
+}
+
+/// A trait to annotate whether a type is `success` or `failure`.
+///
+/// # Deriving
+/// With the `derive` feature, which is enabled by default, `Failure` can be derived for enums.
+/// Variants marked with `#[failure]` are failures, all other variants are successes.
+/// The examples in this section only run with the feature enabled.
+///
+#[cfg_attr(feature = "derive", doc = "```")]
+#[cfg_attr(not(feature = "derive"), doc = "```ignore")]
+/// use scoped::{scoped, Failure};
+/// use std::cell::Cell;
+///
+/// #[derive(Failure)]
+/// enum Outcome {
+///     Done,
+///     Retry(u32),
+///     #[failure]
+///     Fatal { reason: &'static str },
+/// }
+///
+/// assert!(!Outcome::Done.is_error());
+/// assert!(!Outcome::Retry(3).is_error());
+/// assert!(Outcome::Fatal { reason: "disk full" }.is_error());
+///
+/// let failed = Cell::new(false);
+/// scoped(|guard| {
+///     guard.on_scope_failure(&failed, |f| f.set(true));
+///     Outcome::Fatal { reason: "disk full" }
+/// });
+/// assert!(failed.get());
+/// ```
+///
+/// Generic enums are supported as well:
+#[cfg_attr(feature = "derive", doc = "```")]
+#[cfg_attr(not(feature = "derive"), doc = "```ignore")]
+/// use scoped::Failure;
+///
+/// #[derive(Failure)]
+/// enum Answer<'a, T: Clone = u8, F: Fn() -> T = fn() -> u8> {
+///     Yes(&'a T),
+///     Later(F),
+///     #[failure]
+///     No,
+/// }
+///
+/// let no: Answer<u8> = Answer::No;
+/// let yes: Answer<u8> = Answer::Yes(&5);
+/// let later: Answer<u8> = Answer::Later(|| 3);
+///
+/// assert!(no.is_error());
+/// assert!(!yes.is_error());
+/// assert!(!later.is_error());
+/// ```
+///
+/// Only enums can derive `Failure`:
+#[cfg_attr(feature = "derive", doc = "```compile_fail")]
+#[cfg_attr(not(feature = "derive"), doc = "```ignore")]
+/// use scoped::Failure;
+///
+/// #[derive(Failure)]
+/// struct NotAnEnum;
+/// ```
+pub trait Failure {
+    /// Returns true if the type is in a failure state, false otherwise.
+    fn is_error(&self) -> bool;
+
+    /// Returns the outcome of a scope that returned this value.
+    ///
+    /// This defaults to `Failure` if [`is_error`](Failure::is_error) returns true, and to `Success` otherwise.
+    /// Override it to return [`Outcome::Indeterminate`] for values that are neither,
+    /// in which case neither the success nor the failure callbacks run, only the exit callbacks do.
+    /// `is_error` is still used where only two states make sense, like by [`scoped_with_predicate`]
+    /// and the combinators like [`AllOk`], so it should return false for an indeterminate value.
+    ///
+    /// # Examples
+    /// ```
+    /// use scoped::{scoped, Failure, Outcome};
+    ///
+    /// enum Vote {
+    ///     Yes,
+    ///     No,
+    ///     Abstain,
+    /// }
+    ///
+    /// impl Failure for Vote {
+    ///     fn is_error(&self) -> bool {
+    ///         matches!(self, Vote::No)
+    ///     }
+    ///
+    ///     fn outcome(&self) -> Outcome {
+    ///         match self {
+    ///             Vote::Yes => Outcome::Success,
+    ///             Vote::No => Outcome::Failure,
+    ///             Vote::Abstain => Outcome::Indeterminate,
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// scoped(|guard| {
+    ///     guard.on_scope_success_fn(|| unreachable!());
+    ///     guard.on_scope_failure_fn(|| unreachable!());
+    ///     guard.on_scope_exit_fn(|| println!("counted"));
+    ///     Vote::Abstain
+    /// });
+    /// ```
+    fn outcome(&self) -> Outcome {
+        Outcome::failed_if(self.is_error())
+    }
+}
+
+/// This also covers aliases of `Result`, like `std::io::Result<T>` or `anyhow::Result<T>`,
+/// whatever the error type is. In particular, a `Result<T, Box<dyn Error>>` is a failure exactly if it is an `Err`,
+/// see [`scoped_boxed_err`] to hand the boxed error to the failure callbacks.
+///
+/// A `Result<T, Infallible>` can't be an `Err`, so a scope returning one always takes the success path:
+/// only its success and exit callbacks run, unless it panics.
+impl<T, E> Failure for Result<T, E> {
+    /// `Ok(T)` is success, `Err(E)` is failure.
+    fn is_error(&self) -> bool {
+        self.is_err()
+    }
+}
+
+impl<T> Failure for Option<T> {
+    /// `Some(T)` is success, `None` is failure.
+    fn is_error(&self) -> bool {
+        self.is_none()
+    }
+}
+
+impl Failure for Outcome {
+    /// `Success` is success, `Failure` is failure.
+    fn is_error(&self) -> bool {
+        *self == Outcome::Failure
+    }
+
+    fn outcome(&self) -> Outcome {
+        *self
+    }
+}
+
+impl From<bool> for Outcome {
+    /// `true` is a success, `false` a failure, like the [`Failure`] impl of `bool`.
+    fn from(success: bool) -> Self {
+        Self::failed_if(!success)
+    }
+}
+
+impl<T, E> From<Result<T, E>> for Outcome {
+    /// `Ok` is a success, `Err` a failure.
+    fn from(result: Result<T, E>) -> Self {
+        Self::of(&result)
+    }
+}
+
+impl Failure for bool {
+    /// `true` is success, `false` is failure.
+    ///
+    /// Note that this is the opposite of what `is_error` returns:
+    /// `false.is_error()` is `true`.
+    fn is_error(&self) -> bool {
+        !*self
+    }
+}
+
+impl Failure for i32 {
+    /// Treated as a process exit code: `0` is success, anything else is failure.
+    fn is_error(&self) -> bool {
+        *self != 0
+    }
+}
+
+impl Failure for u8 {
+    /// Treated as a process exit code: `0` is success, anything else is failure.
+    fn is_error(&self) -> bool {
+        *self != 0
+    }
+}
+
+impl<B, C> Failure for ControlFlow<B, C> {
+    /// `Continue(C)` is success, `Break(B)` is failure.
+    ///
+    /// Breaking is treated as leaving the happy path early,
+    /// just like `?` does for `Err` and `None`.
+    fn is_error(&self) -> bool {
+        self.is_break()
+    }
+}
+
+impl<T> Failure for Poll<T> {
+    /// `Ready(T)` is success, `Pending` is failure.
+    ///
+    /// A pending poll isn't an error as such, but it means the scope did not achieve its goal (yet),
+    /// which makes the failure callbacks the natural place to prepare a retry.
+    /// The value of a ready poll is not looked at, see [`PollResult`] to also fail on `Ready(Err(..))`.
+    fn is_error(&self) -> bool {
+        self.is_pending()
+    }
+}
+
+/// Lets scopes that never return, because they loop forever or exit the process, type as `Infallible`.
+/// The callbacks of such a scope only ever run if it panics.
+///
+/// ```no_run
+/// use scoped::scoped;
+/// use std::convert::Infallible;
+///
+/// scoped(|guard| -> Infallible {
+///     guard.on_scope_exit_fn(|| println!("shutting down"));
+///     loop {
+///         // serve requests
+///     }
+/// });
+/// ```
+impl Failure for Infallible {
+    /// There are no values of `Infallible`, so this can't be called.
+    fn is_error(&self) -> bool {
+        match *self {}
+    }
+}
+
+macro_rules! impl_failure_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Failure),+> Failure for ($($name,)+) {
+            /// A failure if any of the elements is a failure, a success if all of them are.
+            #[allow(non_snake_case)]
+            fn is_error(&self) -> bool {
+                let ($($name,)+) = self;
+                false $(|| $name.is_error())+
+            }
+        }
+    };
+}
+
+/// References delegate to the value they point to.
+impl<T: Failure + ?Sized> Failure for &T {
+    fn is_error(&self) -> bool {
+        (**self).is_error()
+    }
+    fn outcome(&self) -> Outcome {
+        (**self).outcome()
+    }
+}
+
+/// Mutable references delegate to the value they point to.
+impl<T: Failure + ?Sized> Failure for &mut T {
+    fn is_error(&self) -> bool {
+        (**self).is_error()
+    }
+    fn outcome(&self) -> Outcome {
+        (**self).outcome()
+    }
+}
+
+/// Boxes delegate to the value they hold.
+impl<T: Failure + ?Sized> Failure for Box<T> {
+    fn is_error(&self) -> bool {
+        (**self).is_error()
+    }
+    fn outcome(&self) -> Outcome {
+        (**self).outcome()
+    }
+}
+
+/// An `Rc` delegates to the value it shares.
+impl<T: Failure + ?Sized> Failure for Rc<T> {
+    fn is_error(&self) -> bool {
+        (**self).is_error()
+    }
+
+    fn outcome(&self) -> Outcome {
+        (**self).outcome()
+    }
+}
+
+/// An `Arc` delegates to the value it shares.
+impl<T: Failure + ?Sized> Failure for Arc<T> {
+    fn is_error(&self) -> bool {
+        (**self).is_error()
+    }
+
+    fn outcome(&self) -> Outcome {
+        (**self).outcome()
+    }
+}
+
+/// A `Cow` delegates to the value it holds, whether borrowed or owned.
+impl<B: Failure + ToOwned + ?Sized> Failure for Cow<'_, B> {
+    fn is_error(&self) -> bool {
+        (**self).is_error()
+    }
+    fn outcome(&self) -> Outcome {
+        (**self).outcome()
+    }
+}
+
+impl_failure_for_tuple!(A, B);
+impl_failure_for_tuple!(A, B, C);
+impl_failure_for_tuple!(A, B, C, D);
+
+/// A nested result that is only a success if both layers are `Ok`.
+///
+/// The [`Failure`] impl of `Result` only looks at the outer layer, so `Ok(Err(..))` counts as a success.
+/// Wrap the nested result in a `DeepResult` to treat the inner `Err` as a failure too,
+/// and unwrap it again with [`DeepResult::into_inner`] once the scope returned.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, DeepResult};
+/// use std::cell::Cell;
+///
+/// fn parse(input: &str) -> Result<Result<u32, String>, std::io::Error> {
+///     Ok(input.parse().map_err(|_| format!("not a number: {}", input)))
+/// }
+///
+/// let rolled_back = Cell::new(false);
+/// let ret = scoped(|guard| {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     DeepResult::new(parse("four"))
+/// });
+///
+/// assert!(rolled_back.get());
+/// assert!(matches!(ret.into_inner(), Ok(Err(_))));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeepResult<T, E1, E2>(pub Result<Result<T, E1>, E2>);
+
+impl<T, E1, E2> DeepResult<T, E1, E2> {
+    /// Wraps `result`.
+    pub fn new(result: Result<Result<T, E1>, E2>) -> Self {
+        Self(result)
+    }
+
+    /// Returns the wrapped result.
+    pub fn into_inner(self) -> Result<Result<T, E1>, E2> {
+        self.0
+    }
+}
+
+impl<T, E1, E2> From<Result<Result<T, E1>, E2>> for DeepResult<T, E1, E2> {
+    fn from(result: Result<Result<T, E1>, E2>) -> Self {
+        Self(result)
+    }
+}
+
+impl<T, E1, E2> Failure for DeepResult<T, E1, E2> {
+    /// `Ok(Ok(T))` is success, both `Ok(Err(E1))` and `Err(E2)` are failures.
+    fn is_error(&self) -> bool {
+        !matches!(self.0, Ok(Ok(_)))
+    }
+}
+
+/// A poll of a fallible operation that is only a success once it is ready and `Ok`.
+///
+/// The [`Failure`] impl of `Poll` only checks for readiness, so `Ready(Err(..))` counts as a success.
+/// Wrap the poll in a `PollResult` to treat it as a failure in these cases:
+/// - `Pending`, as the operation did not complete (yet),
+/// - `Ready(Err(E))`, as the operation completed, but failed.
+///
+/// Only `Ready(Ok(T))` is a success.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, PollResult};
+/// use std::cell::Cell;
+/// use std::task::Poll;
+///
+/// let rolled_back = Cell::new(false);
+/// let ret = scoped(|guard| {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     PollResult::new(Poll::Ready(Err::<usize, _>("connection reset")))
+/// });
+///
+/// assert!(rolled_back.get());
+/// assert_eq!(ret.into_inner(), Poll::Ready(Err("connection reset")));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PollResult<T, E>(pub Poll<Result<T, E>>);
+
+impl<T, E> PollResult<T, E> {
+    /// Wraps `poll`.
+    pub fn new(poll: Poll<Result<T, E>>) -> Self {
+        Self(poll)
+    }
+
+    /// Returns the wrapped poll.
+    pub fn into_inner(self) -> Poll<Result<T, E>> {
+        self.0
+    }
+}
+
+impl<T, E> From<Poll<Result<T, E>>> for PollResult<T, E> {
+    fn from(poll: Poll<Result<T, E>>) -> Self {
+        Self(poll)
+    }
+}
+
+impl<T, E> Failure for PollResult<T, E> {
+    /// `Ready(Ok(T))` is success, both `Pending` and `Ready(Err(E))` are failures.
+    fn is_error(&self) -> bool {
+        !matches!(self.0, Poll::Ready(Ok(_)))
+    }
+}
+
+/// A collection of results that is a success only if all of them are `Ok`.
+///
+/// An empty collection is a success, as none of its results failed.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, AllOk};
+/// use std::cell::Cell;
+///
+/// let rolled_back = Cell::new(false);
+/// scoped(|guard| {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     AllOk::new(["1", "two", "3"].iter().map(|s| s.parse::<u32>()).collect())
+/// });
+///
+/// assert!(rolled_back.get());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AllOk<T, E>(pub Vec<Result<T, E>>);
+
+impl<T, E> AllOk<T, E> {
+    /// Wraps `results`.
+    pub fn new(results: Vec<Result<T, E>>) -> Self {
+        Self(results)
+    }
+
+    /// Returns the wrapped results.
+    pub fn into_inner(self) -> Vec<Result<T, E>> {
+        self.0
+    }
+}
+
+impl<T, E> From<Vec<Result<T, E>>> for AllOk<T, E> {
+    fn from(results: Vec<Result<T, E>>) -> Self {
+        Self(results)
+    }
+}
+
+impl<T, E> Failure for AllOk<T, E> {
+    /// A failure if any of the results is an `Err`.
+    fn is_error(&self) -> bool {
+        self.0.iter().any(Result::is_err)
+    }
+}
+
+/// A collection of results that is a success if at least one of them is `Ok`.
+///
+/// An empty collection is a failure, as none of its results succeeded.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, AnyOk};
+/// use std::cell::Cell;
+///
+/// let rolled_back = Cell::new(false);
+/// scoped(|guard| {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     AnyOk::new(["1", "two", "3"].iter().map(|s| s.parse::<u32>()).collect())
+/// });
+///
+/// assert!(!rolled_back.get());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AnyOk<T, E>(pub Vec<Result<T, E>>);
+
+impl<T, E> AnyOk<T, E> {
+    /// Wraps `results`.
+    pub fn new(results: Vec<Result<T, E>>) -> Self {
+        Self(results)
+    }
+
+    /// Returns the wrapped results.
+    pub fn into_inner(self) -> Vec<Result<T, E>> {
+        self.0
+    }
+}
+
+impl<T, E> From<Vec<Result<T, E>>> for AnyOk<T, E> {
+    fn from(results: Vec<Result<T, E>>) -> Self {
+        Self(results)
+    }
+}
+
+impl<T, E> Failure for AnyOk<T, E> {
+    /// A failure if all of the results are `Err`s.
+    fn is_error(&self) -> bool {
+        !self.0.iter().any(Result::is_ok)
+    }
+}
+
+/// A count that is a success if it is nonzero, and a failure if it is `0`.
+///
+/// This is the opposite polarity of the [`Failure`] impls of `i32` and `u8`, which treat integers as exit codes.
+/// Wrap a count, like the number of bytes written or rows affected, in a `NonzeroSuccess`
+/// to treat "nothing happened" as a failure.
+/// An `Option<NonZeroU32>` and the like already work without a wrapper, `None` being the failure.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, NonzeroSuccess};
+/// use std::cell::Cell;
+///
+/// let rolled_back = Cell::new(false);
+/// let written = scoped(|guard| {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     NonzeroSuccess(0u32)
+/// });
+///
+/// assert!(rolled_back.get());
+/// assert_eq!(written.into_inner(), 0);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NonzeroSuccess<T>(pub T);
+
+impl<T> NonzeroSuccess<T> {
+    /// Returns the wrapped count.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+macro_rules! impl_failure_for_nonzero_success {
+    ($($ty:ty),+) => {$(
+        impl Failure for NonzeroSuccess<$ty> {
+            /// `0` is failure, anything else is success.
+            fn is_error(&self) -> bool {
+                self.0 == 0
+            }
+        }
+    )+};
+}
+
+impl_failure_for_nonzero_success!(u32, u64, usize);
+
+/// A value that is a success if it lies within a range, and a failure otherwise.
+///
+/// This maps any ordered outcome onto success and failure, like HTTP status codes,
+/// where `200..=399` is a success, or a measurement that has to stay within bounds.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, RangeSuccess};
+/// use std::cell::Cell;
+///
+/// let retried = Cell::new(false);
+/// let ret = scoped(|guard| {
+///     guard.on_scope_failure(&retried, |r| r.set(true));
+///     let code = 503;
+///     RangeSuccess(code, 200..=399)
+/// });
+///
+/// assert!(retried.get());
+/// assert_eq!(ret.into_inner(), 503);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RangeSuccess<T>(pub T, pub RangeInclusive<T>);
+
+impl<T> RangeSuccess<T> {
+    /// Pairs `value` with the `range` of successful values.
+    pub fn new(value: T, range: RangeInclusive<T>) -> Self {
+        Self(value, range)
+    }
+
+    /// Returns the wrapped value, dropping the range.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: PartialOrd> Failure for RangeSuccess<T> {
+    /// A failure if the value lies outside of the range.
+    fn is_error(&self) -> bool {
+        !self.1.contains(&self.0)
+    }
+}
+
+/// An error message, which is a failure unless it is empty.
+///
+/// This suits functions reporting errors as a `String`, with an empty string meaning success.
+/// `String` itself does not implement [`Failure`], as an empty string being a success would be surprising elsewhere.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, MsgOutcome};
+/// use std::cell::Cell;
+///
+/// fn validate(name: &str) -> String {
+///     if name.is_empty() { "the name is missing".to_string() } else { String::new() }
+/// }
+///
+/// let rejected = Cell::new(false);
+/// let ret = scoped(|guard| {
+///     guard.on_scope_failure(&rejected, |r| r.set(true));
+///     MsgOutcome(validate(""))
+/// });
+///
+/// assert!(rejected.get());
+/// assert_eq!(ret.into_inner(), "the name is missing");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MsgOutcome(pub String);
+
+impl MsgOutcome {
+    /// Wraps the error message `msg`, where an empty message means success.
+    pub fn new(msg: impl Into<String>) -> Self {
+        Self(msg.into())
+    }
+
+    /// Returns the wrapped message.
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl Failure for MsgOutcome {
+    /// A failure if the message is not empty.
+    fn is_error(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+/// A value paired with a predicate deciding whether it is a failure.
+///
+/// This lets a scope return a type without a [`Failure`] impl, without a dedicated entry point
+/// like [`scoped_with_predicate`]. The predicate is `Fn` rather than `FnOnce`,
+/// as [`Failure::is_error`] only borrows the value.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped, FailureWith};
+/// use std::cell::Cell;
+///
+/// struct Response {
+///     status: u16,
+/// }
+///
+/// let rolled_back = Cell::new(false);
+/// let ret = scoped(|guard| {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     FailureWith(Response { status: 503 }, |r: &Response| r.status >= 500)
+/// });
+///
+/// assert!(rolled_back.get());
+/// assert_eq!(ret.into_inner().status, 503);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct FailureWith<R, F>(pub R, pub F);
+
+impl<R, F: Fn(&R) -> bool> FailureWith<R, F> {
+    /// Pairs `value` with `is_failure`, which returns `true` if `value` is a failure.
+    pub fn new(value: R, is_failure: F) -> Self {
+        Self(value, is_failure)
+    }
+}
+
+impl<R, F> FailureWith<R, F> {
+    /// Returns the wrapped value, dropping the predicate.
+    pub fn into_inner(self) -> R {
+        self.0
+    }
+}
+
+impl<R, F: Fn(&R) -> bool> Failure for FailureWith<R, F> {
+    /// A failure if the predicate returns `true` for the wrapped value.
+    fn is_error(&self) -> bool {
+        (self.1)(&self.0)
+    }
+}
+
+/// Executes the scope `scope`.
+/// A scope is a closure, in which access to a guard is granted.
+/// A guard is used to schedule callbacks to run on a scope's success, failure, or exit, using
+/// [`Guard::on_scope_success`], [`Guard::on_scope_failure`], [`Guard::on_scope_exit`].
+///
+/// Its important to note that callbacks scheduled with [`Guard::on_scope_exit`] will *always* run, and will always run last.
+///
+/// # Order
+/// Once the scope returned, the callbacks run in a fixed order, regardless of the order they were scheduled in:
+/// 1. all success callbacks, or all failure callbacks, last scheduled first,
+/// 2. all callbacks scheduled with [`Guard::on_scope_finish`], last scheduled first,
+/// 3. all exit callbacks, last scheduled first, unless [`Guard::exit_order`] says otherwise.
+///
+/// # Panics
+/// If `scope` panics, the failure callbacks and then the exit callbacks are run while unwinding,
+/// after which the panic continues to propagate. The same holds for a panic inside a success or failure callback,
+/// in which case the exit callbacks still run. A callback that panics *while* unwinding aborts the process.
+///
+/// With the `std` feature, a panic in an exit callback does not keep the other exit callbacks from running,
+/// the panic is resumed once they ran. See [`Guard::catch_panics`].
+///
+/// # Examples
+/// ```
+/// use scoped::{Guard, scoped};
+///
+/// fn main() {
+///     use std::cell::Cell;
+///
+///     let mut number = Cell::new(0);
+///
+///     scoped(|guard| -> Result<(), ()> {     
+///         
+///         guard.on_scope_exit(&number, move |n| {
+///             assert_eq!(n.get(), 2);
+///             n.set(3);
+///         });
+///
+///         guard.on_scope_success(&number, move |n| {
+///             assert_eq!(n.get(), 1);
+///             n.set(2);
+///         });
+///
+///         number.set(1);
+///         Ok(())
+///     });
+///     assert_eq!(number.get(), 3);
+/// }
+/// ```
+pub fn scoped<'a, R: Failure>(scope: impl FnOnce(&mut Guard<'a>) -> R) -> R {
+    scoped_in(Guard::default(), scope, Outcome::of)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, handing it a shared reference to the guard.
+///
+/// All scheduling methods take `&self`, so a shared reference is all most scopes need,
+/// and it can be passed to any number of helpers without reborrowing.
+/// Methods that take `&mut self`, like [`Guard::cancel`], are not available with it.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped_shared, Guard};
+///
+/// fn open<'a>(guard: &Guard<'a>, name: &'static str) {
+///     guard.on_scope_exit(name, |name| println!("closing {}", name));
+/// }
+///
+/// scoped_shared(|guard| {
+///     open(guard, "a.txt");
+///     open(guard, "b.txt");
+///     Some(())
+/// });
+/// ```
+pub fn scoped_shared<'a, R: Failure>(scope: impl FnOnce(&Guard<'a>) -> R) -> R {
+    scoped_in(Guard::default(), |guard| scope(guard), Outcome::of)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does,
+/// with a guard that has room for the given number of callbacks per phase.
+///
+/// See [`Guard::with_capacity`].
+pub fn scoped_with_capacity<'a, R: Failure>(
+    success: usize,
+    failure: usize,
+    exit: usize,
+    scope: impl FnOnce(&mut Guard<'a>) -> R,
+) -> R {
+    scoped_in(
+        Guard::with_capacity(success, failure, exit),
+        scope,
+        Outcome::of,
+    )
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does,
+/// returning the values of the callbacks scheduled with [`Guard::on_scope_exit_map`] alongside its result.
+///
+/// The values are boxed as [`Any`], in the order their callbacks ran,
+/// so they have to be downcast back to the type their callback returned.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_collect;
+///
+/// let (ret, collected) = scoped_collect(|guard| {
+///     let buf = guard.on_scope_exit_map(Vec::new(), |buf| buf);
+///     buf.extend_from_slice(b"hello");
+///     guard.on_scope_exit_map("world", str::len);
+///     Some(())
+/// });
+///
+/// assert_eq!(ret, Some(()));
+/// assert_eq!(collected[0].downcast_ref::<usize>(), Some(&5));
+///
+/// let buf = collected.into_iter().nth(1).unwrap().downcast::<Vec<u8>>().unwrap();
+/// assert_eq!(*buf, b"hello");
+/// ```
+pub fn scoped_collect<'a, R: Failure>(
+    scope: impl FnOnce(&mut Guard<'a>) -> R,
+) -> (R, Vec<Box<dyn Any>>) {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard {
+        guard: Guard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+    guard.finish(Outcome::of(&ret));
+
+    let collected = guard
+        .collected
+        .take()
+        .map_or_else(Vec::new, |collected| collected.take());
+    (ret, collected)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does,
+/// handing the error it returns to the callbacks scheduled with [`Guard::on_scope_failure_with_error`].
+pub fn scoped_result<'a, T, E: 'static>(
+    scope: impl FnOnce(&mut Guard<'a>) -> Result<T, E>,
+) -> Result<T, E> {
+    result_in(scope, |_| None)
+}
+
+/// Executes the scope `scope`, like [`scoped_result`] does,
+/// also handing the value it returns to the callbacks scheduled with [`Guard::on_scope_exit_with_value`].
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_value;
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+/// let ret = scoped_value(|guard| {
+///     guard.on_scope_exit_with_value(&log, |log, path: Option<&String>| {
+///         log.borrow_mut().push(path.cloned().unwrap_or_default());
+///     });
+///     Ok::<_, ()>("out.txt".to_string())
+/// });
+///
+/// assert_eq!(ret, Ok("out.txt".to_string()));
+/// assert_eq!(*log.borrow(), vec!["out.txt"]);
+/// ```
+pub fn scoped_value<'a, T: 'static, E: 'static>(
+    scope: impl FnOnce(&mut Guard<'a>) -> Result<T, E>,
+) -> Result<T, E> {
+    result_in(scope, |value| Some(NonNull::from(value as &dyn Any)))
+}
+
+/// Drives `scope`, pointing the error slot to the error it returned, and the value slot to what `value` makes of its value.
+fn result_in<'a, T, E: 'static>(
+    scope: impl FnOnce(&mut Guard<'a>) -> Result<T, E>,
+    value: impl FnOnce(&T) -> Option<NonNull<dyn Any>>,
+) -> Result<T, E> {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard {
+        guard: Guard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+
+    let error_slot = guard.error.take();
+    let value_slot = guard.value.take();
+    match (&ret, &error_slot, &value_slot) {
+        (Err(error), Some(slot), _) => slot.set(Some(NonNull::from(error as &dyn Any))),
+        (Ok(ok), _, Some(slot)) => slot.set(value(ok)),
+        _ => {}
+    }
+    guard.finish(Outcome::of(&ret));
+    for slot in error_slot.iter().chain(&value_slot) {
+        slot.set(None);
+    }
+
+    ret
+}
+
+/// Executes the scope `scope`, like [`scoped_result`] does, for scopes failing with a boxed error.
+///
+/// The error is handed to the callbacks scheduled with [`Guard::on_scope_failure_with_source`],
+/// and then returned as is. Any error converts into the box with `?`.
+#[cfg(feature = "std")]
+pub fn scoped_boxed_err<'a, T>(
+    scope: impl FnOnce(&mut Guard<'a>) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    scoped_result(scope)
+}
+
+/// The result of two scopes chained with [`scoped_then`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Chained<R1, R2> {
+    /// Both scopes succeeded.
+    Done(R1, R2),
+
+    /// The first scope failed, or its outcome was indeterminate, so the second one did not run.
+    FirstFailed(R1),
+
+    /// The first scope succeeded, but the second one failed, or its outcome was indeterminate.
+    SecondFailed(R1, R2),
+}
+
+impl<R1, R2> Failure for Chained<R1, R2> {
+    /// `Done` is success, both `FirstFailed` and `SecondFailed` are failures.
+    fn is_error(&self) -> bool {
+        !matches!(self, Chained::Done(..))
+    }
+}
+
+/// Executes the scope `first`, and if it succeeded, the scope `second`, as if `second` was nested in `first`.
+///
+/// The chain only succeeds if both scopes do:
+/// - If `first` does not succeed, `second` does not run, and the callbacks of `first` run for its outcome.
+/// - Otherwise the callbacks of `second` run for its own outcome, followed by those of `first` for the same outcome,
+///   which thus only run their success callbacks if `second` succeeded as well.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped_then, Chained};
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+///
+/// let ret = scoped_then(
+///     |guard| {
+///         guard.on_scope_success(&log, |l| l.borrow_mut().push("committed download"));
+///         guard.on_scope_failure(&log, |l| l.borrow_mut().push("removed download"));
+///         Some("archive.zip")
+///     },
+///     |guard| {
+///         guard.on_scope_failure(&log, |l| l.borrow_mut().push("removed extracted files"));
+///         None::<()>
+///     },
+/// );
+///
+/// assert_eq!(ret, Chained::SecondFailed(Some("archive.zip"), None));
+/// assert_eq!(*log.borrow(), vec!["removed extracted files", "removed download"]);
+/// ```
+pub fn scoped_then<'a, 'b, R1: Failure, R2: Failure>(
+    first: impl FnOnce(&mut Guard<'a>) -> R1,
+    second: impl FnOnce(&mut Guard<'b>) -> R2,
+) -> Chained<R1, R2> {
+    // The outcome the callbacks of `first` run for, that of whichever scope did not succeed.
+    let outcome = Cell::new(Outcome::Success);
+
+    let body = |guard: &mut Guard<'a>| {
+        let first = first(guard);
+        let first_outcome = guard.decide(Outcome::of(&first));
+        if first_outcome != Outcome::Success {
+            outcome.set(first_outcome);
+            return Chained::FirstFailed(first);
+        }
+
+        // Drive `second` by hand, so a scope marked as failing counts as failed, see `scoped_retry`.
+        let _depth = Depth::enter();
+        let mut inner = PanicGuard {
+            guard: Guard::default(),
+        };
+        let ret = second(&mut inner.guard);
+        let second_outcome = inner.guard.decide(Outcome::of(&ret));
+        inner.guard.finish(second_outcome);
+        outcome.set(second_outcome);
+
+        if second_outcome != Outcome::Success {
+            Chained::SecondFailed(first, ret)
+        } else {
+            Chained::Done(first, ret)
+        }
+    };
+    scoped_in(Guard::default(), body, |_| outcome.get())
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, with a state that both the scope and its callbacks can borrow.
+///
+/// The guard lives no longer than the borrow of `state`, so callbacks can capture `&state` by move,
+/// without wrapping it in an `Rc`. Once the callbacks ran, `state` is handed back along with the result.
+///
+/// As the guard's lifetime is tied to `state`, callbacks can't borrow other locals from outside of the scope.
+/// Put such borrows into the state instead.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_with_state;
+/// use std::cell::Cell;
+///
+/// let (ret, cleaned) = scoped_with_state(Cell::new(0), |guard, cleaned| {
+///     for name in ["a.txt", "b.txt"] {
+///         guard.on_scope_exit(name, move |_| cleaned.set(cleaned.get() + 1));
+///     }
+///     assert_eq!(cleaned.get(), 0);
+///     Some(())
+/// });
+///
+/// assert_eq!(ret, Some(()));
+/// assert_eq!(cleaned.into_inner(), 2);
+/// ```
+pub fn scoped_with_state<S, R: Failure>(
+    state: S,
+    scope: impl for<'s> FnOnce(&mut Guard<'s>, &'s S) -> R,
+) -> (R, S) {
+    let ret = scoped(|guard| scope(guard, &state));
+    (ret, state)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, retrying it up to `retries` times while it fails.
+///
+/// Every attempt gets a fresh guard. Once an attempt failed and there are retries left:
+/// - its failure callbacks and finish callbacks run right away, before the next attempt,
+/// - its success callbacks are discarded,
+/// - its exit callbacks are held back until the last attempt is done.
+///
+/// The last attempt, be it the first one that succeeded or the one that used up the retries,
+/// finishes like a regular scope. Afterwards the held back exit callbacks run, those of later attempts first.
+/// If an attempt panics, there are no further attempts.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_retry;
+/// use std::cell::Cell;
+///
+/// let attempts = Cell::new(0);
+///
+/// let ret = scoped_retry(3, |guard| {
+///     attempts.set(attempts.get() + 1);
+///     guard.on_scope_failure_fn(|| println!("attempt failed, cleaning up"));
+///     if attempts.get() < 2 { Err("timed out") } else { Ok(attempts.get()) }
+/// });
+///
+/// assert_eq!(ret, Ok(2));
+/// ```
+pub fn scoped_retry<'a, R: Failure>(
+    retries: usize,
+    mut scope: impl FnMut(&mut Guard<'a>) -> R,
+) -> R {
+    let _depth = Depth::enter();
+    // Holds the exit callbacks of the failed attempts.
+    let mut failed = PanicGuard {
+        guard: Guard::default(),
+    };
+
+    for retries_left in (0..=retries).rev() {
+        let mut attempt = PanicGuard {
+            guard: Guard::default(),
+        };
+        let guard = &mut attempt.guard;
+
+        let ret = scope(guard);
+        let outcome = guard.decide(Outcome::of(&ret));
+
+        if outcome == Outcome::Failure && retries_left > 0 {
+            let failure_ran = guard.execute(When::Failure, outcome);
+            guard.execute(When::Finish, outcome);
+            // The held back exit callbacks still see the counts of their own attempt.
+            guard.set_counts(PhaseCounts {
+                success_ran: 0,
+                failure_ran,
+            });
+            guard.on_scope_success.clear();
+            let exit = core::mem::take(&mut guard.on_scope_exit);
+            failed.guard.on_scope_exit.append(exit);
+            continue;
+        }
+
+        guard.finish(outcome);
+        drop(attempt);
+        failed.guard.finish(outcome);
+        return ret;
+    }
+
+    unreachable!("the last attempt always returns")
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, reporting which callbacks ran.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped_report, Outcome};
+///
+/// let (ret, report) = scoped_report(|guard| {
+///     guard.on_scope_failure_fn(|| println!("rolling back"));
+///     guard.on_scope_exit_fn(|| println!("closing"));
+///     None::<()>
+/// });
+///
+/// assert_eq!(ret, None);
+/// assert_eq!(report.outcome, Outcome::Failure);
+/// assert_eq!((report.success_ran, report.failure_ran, report.exit_ran), (0, 1, 1));
+/// ```
+pub fn scoped_report<'a, R: Failure>(scope: impl FnOnce(&mut Guard<'a>) -> R) -> (R, ScopeReport) {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard {
+        guard: Guard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+    let report = guard.finish(Outcome::of(&ret));
+    (ret, report)
+}
+
+/// Diagnostics about a scope's callbacks, as gathered by [`scoped_keep`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GuardStats {
+    /// Which callbacks ran.
+    pub report: ScopeReport,
+
+    /// The number of callbacks dropped without running,
+    /// because they were scheduled for the branch the scope did not take.
+    pub discarded: usize,
+
+    /// The number of callbacks still pending once all phases ran, which should always be `0`.
+    pub pending: usize,
+
+    /// The number of callbacks that panicked. Always `0` without the `std` feature.
+    pub panics: usize,
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, gathering diagnostics about its callbacks.
+///
+/// Unlike [`scoped_report`], this also counts the callbacks that were discarded or left pending.
+/// Callbacks that panic are caught in every phase and counted in [`GuardStats::panics`],
+/// instead of resuming the panic once the remaining callbacks ran.
+/// A panic of the scope itself still runs the failure and exit callbacks while unwinding, like with [`scoped`](fn@scoped).
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped_keep, Outcome};
+///
+/// let (ret, stats) = scoped_keep(|guard| {
+///     guard.on_scope_success_fn(|| println!("committing"));
+///     guard.on_scope_failure_fn(|| println!("rolling back"));
+///     guard.on_scope_exit_fn(|| println!("closing"));
+///     Some(())
+/// });
+///
+/// assert_eq!(ret, Some(()));
+/// assert_eq!(stats.report.outcome, Outcome::Success);
+/// assert_eq!((stats.report.success_ran, stats.report.exit_ran), (1, 1));
+/// assert_eq!((stats.discarded, stats.pending, stats.panics), (1, 0, 0));
+/// ```
+pub fn scoped_keep<'a, R: Failure>(scope: impl FnOnce(&mut Guard<'a>) -> R) -> (R, GuardStats) {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard {
+        guard: Guard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    #[cfg(feature = "std")]
+    for when in [When::Success, When::Failure, When::Finish, When::Exit] {
+        let deferring = guard.deferring(when);
+        deferring.catch_panics.set(true);
+        deferring.count_panics.set(true);
+    }
+
+    let ret = scope(guard);
+    let outcome = guard.decide(Outcome::of(&ret));
+    let discarded = match outcome {
+        Outcome::Success => guard.pending_failure(),
+        Outcome::Failure => guard.pending_success(),
+        Outcome::Indeterminate => guard.pending() - guard.pending_exit(),
+    };
+    let report = guard.finish(outcome);
+    if outcome == Outcome::Failure {
+        guard.on_scope_success.clear();
+    }
+
+    #[cfg(feature = "std")]
+    let panics = [When::Success, When::Failure, When::Finish, When::Exit]
+        .iter()
+        .map(|&when| guard.deferring(when).panics.get())
+        .sum();
+    #[cfg(not(feature = "std"))]
+    let panics = 0;
+
+    let stats = GuardStats {
+        report,
+        discarded,
+        pending: guard.pending(),
+        panics,
+    };
+    (ret, stats)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, with the prepared `guard`.
+///
+/// The guard keeps its configuration, like its capacity, limit and exit order,
+/// and the callbacks already scheduled on it run along with the ones the scope schedules,
+/// as if the scope scheduled them first.
+///
+/// # Examples
+/// ```
+/// use scoped::{scoped_guard, Guard};
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+///
+/// let guard = Guard::with_limit(8);
+/// guard.on_scope_exit(&log, |l| l.borrow_mut().push("prepared"));
+///
+/// scoped_guard(guard, |guard| {
+///     guard.on_scope_exit(&log, |l| l.borrow_mut().push("scheduled"));
+///     Some(())
+/// });
+///
+/// assert_eq!(*log.borrow(), vec!["scheduled", "prepared"]);
+/// ```
+pub fn scoped_guard<'a, R: Failure>(
+    guard: Guard<'a>,
+    scope: impl FnOnce(&mut Guard<'a>) -> R,
+) -> R {
+    scoped_in(guard, scope, Outcome::of)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does,
+/// with a guard on which at most `max` callbacks can be pending at once.
+///
+/// See [`Guard::with_limit`].
+pub fn scoped_with_limit<'a, R: Failure>(max: usize, scope: impl FnOnce(&mut Guard<'a>) -> R) -> R {
+    scoped_in(Guard::with_limit(max), scope, Outcome::of)
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does,
+/// but decides whether the scope failed with `is_failure` instead of the [`Failure`] impl of `R`.
+///
+/// This is useful when the meaning of a return value depends on the call site,
+/// and `R` doesn't even have to implement [`Failure`].
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_with_predicate;
+/// use std::cell::Cell;
+///
+/// let rolled_back = Cell::new(false);
+///
+/// // Reading zero bytes counts as a failure here, even though it is `Ok`.
+/// let read = scoped_with_predicate(
+///     |guard| {
+///         guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///         Ok::<usize, ()>(0)
+///     },
+///     |read| !matches!(read, Ok(n) if *n > 0),
+/// );
+///
+/// assert_eq!(read, Ok(0));
+/// assert!(rolled_back.get());
+/// ```
+pub fn scoped_with_predicate<'a, R>(
+    scope: impl FnOnce(&mut Guard<'a>) -> R,
+    is_failure: impl FnOnce(&R) -> bool,
+) -> R {
+    scoped_in(Guard::default(), scope, |ret| {
+        Outcome::failed_if(is_failure(ret))
+    })
+}
+
+/// Drives `scope` with `guard`, deciding on the outcome with `outcome`.
+fn scoped_in<'a, R>(
+    guard: Guard<'a>,
+    scope: impl FnOnce(&mut Guard<'a>) -> R,
+    outcome: impl FnOnce(&R) -> Outcome,
+) -> R {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard { guard };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+    guard.finish(outcome(&ret));
+    ret
+}
+
+/// Executes the scope `scope`, treating a panic inside of it as a failure.
+///
+/// This behaves like [`scoped`](fn@scoped), except that a panic in `scope` is caught with [`std::panic::catch_unwind`].
+/// When that happens, the failure callbacks and then the exit callbacks are run as if the scope returned normally,
+/// after which the panic is resumed with [`std::panic::resume_unwind`]. The panic is thus never swallowed,
+/// it merely gets delayed until the callbacks finished. Because the callbacks don't run while unwinding,
+/// a callback that panics here does not abort the process.
+///
+/// `scope` is not required to be [`UnwindSafe`](std::panic::UnwindSafe).
+/// The failure callbacks are exactly the place to deal with state a panic might have left behind,
+/// so it is up to them to not observe broken invariants.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_catch_unwind;
+/// use std::cell::Cell;
+/// use std::panic::{self, AssertUnwindSafe};
+///
+/// let failed = Cell::new(false);
+///
+/// let result = panic::catch_unwind(AssertUnwindSafe(|| {
+///     scoped_catch_unwind(|guard| -> Result<(), ()> {
+///         guard.on_scope_failure(&failed, |f| f.set(true));
+///         panic!("oh no");
+///     })
+/// }));
+///
+/// assert!(result.is_err());
+/// assert!(failed.get());
+/// ```
+#[cfg(feature = "std")]
+pub fn scoped_catch_unwind<'a, R: Failure>(scope: impl FnOnce(&mut Guard<'a>) -> R) -> R {
+    catch_unwind_in(Guard::default(), scope)
+}
+
+/// Drives `scope` with `guard`, catching a panic like [`scoped_catch_unwind`] does.
+#[cfg(feature = "std")]
+fn catch_unwind_in<'a, R: Failure>(guard: Guard<'a>, scope: impl FnOnce(&mut Guard<'a>) -> R) -> R {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard { guard };
+    let guard = &mut panic_guard.guard;
+
+    match panic::catch_unwind(AssertUnwindSafe(|| scope(&mut *guard))) {
+        Ok(ret) => {
+            guard.finish(Outcome::of(&ret));
+            ret
+        }
+        Err(payload) => {
+            guard.finish(Outcome::Failure);
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+/// How long the parts of a scope took, as measured by [`scoped_timed`].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ScopeTiming {
+    /// The time spent in the scope itself.
+    pub body: std::time::Duration,
+
+    /// The time spent running the callbacks, once the scope returned.
+    pub callbacks: std::time::Duration,
+}
+
+/// Executes the scope `scope`, like [`scoped`](fn@scoped) does, measuring how long the scope and its callbacks took.
+///
+/// If the scope panics, nothing is measured, the callbacks run while unwinding just like with [`scoped`](fn@scoped).
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_timed;
+///
+/// let (ret, timing) = scoped_timed(|guard| {
+///     guard.on_scope_exit_fn(|| println!("cleaning up"));
+///     Some(5)
+/// });
+///
+/// assert_eq!(ret, Some(5));
+/// println!("scope: {:?}, callbacks: {:?}", timing.body, timing.callbacks);
+/// ```
+#[cfg(feature = "std")]
+pub fn scoped_timed<'a, R: Failure>(scope: impl FnOnce(&mut Guard<'a>) -> R) -> (R, ScopeTiming) {
+    let _depth = Depth::enter();
+    let mut panic_guard = PanicGuard {
+        guard: Guard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let start = std::time::Instant::now();
+    let ret = scope(guard);
+    let body = start.elapsed();
+
+    let start = std::time::Instant::now();
+    guard.finish(Outcome::of(&ret));
+    let callbacks = start.elapsed();
+
+    (ret, ScopeTiming { body, callbacks })
+}
+
+/// Configures a scope before running it.
+///
+/// This combines the knobs of the `scoped_*` functions, which remain available as shorthands.
+///
+/// # Examples
+/// ```
+/// use scoped::{Order, ScopeBuilder};
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+///
+/// ScopeBuilder::new()
+///     .capacity(0, 0, 2)
+///     .exit_order(Order::Fifo)
+///     .limit(2)
+///     .run(|guard| {
+///         guard.on_scope_exit(&log, |l| l.borrow_mut().push("opened"));
+///         guard.on_scope_exit(&log, |l| l.borrow_mut().push("closed"));
+///         Some(())
+///     });
+///
+/// assert_eq!(*log.borrow(), vec!["opened", "closed"]);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScopeBuilder {
+    capacity: (usize, usize, usize),
+    exit_order: Order,
+    limit: Option<usize>,
+    #[cfg(feature = "std")]
+    catch_panic: bool,
+}
+
+impl ScopeBuilder {
+    /// Creates a builder for a scope that behaves like [`scoped`](fn@scoped).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves room for callbacks per phase, see [`Guard::with_capacity`].
+    pub fn capacity(mut self, success: usize, failure: usize, exit: usize) -> Self {
+        self.capacity = (success, failure, exit);
+        self
+    }
+
+    /// Sets the order of the exit callbacks, see [`Guard::exit_order`].
+    pub fn exit_order(mut self, order: Order) -> Self {
+        self.exit_order = order;
+        self
+    }
+
+    /// Limits the number of pending callbacks, see [`Guard::with_limit`].
+    pub fn limit(mut self, max: usize) -> Self {
+        self.limit = Some(max);
+        self
+    }
+
+    /// Sets whether a panic inside the scope is caught until the callbacks ran, see [`scoped_catch_unwind`].
+    #[cfg(feature = "std")]
+    pub fn catch_panic(mut self, catch_panic: bool) -> Self {
+        self.catch_panic = catch_panic;
+        self
+    }
+
+    /// Executes the scope `scope` with a guard configured by this builder.
+    pub fn run<'a, R: Failure>(self, scope: impl FnOnce(&mut Guard<'a>) -> R) -> R {
+        let (success, failure, exit) = self.capacity;
+        let mut guard = Guard::with_capacity(success, failure, exit);
+        guard.limit = self.limit;
+        guard.exit_order(self.exit_order);
+
+        #[cfg(feature = "std")]
+        if self.catch_panic {
+            return catch_unwind_in(guard, scope);
+        }
+
+        scoped_in(guard, scope, Outcome::of)
+    }
+}
+
+/// Executes the asynchronous scope `scope`.
+///
+/// This is the asynchronous counterpart of [`scoped`](fn@scoped): `scope` receives the guard and returns a future,
+/// which gets awaited before the callbacks run. The callbacks themselves are synchronous.
+///
+/// Because the future borrows the guard, `scope` has to return it boxed and pinned,
+/// which is easiest done with `Box::pin(async move { .. })`.
+///
+/// If the returned future is dropped before it completes, the scope is considered to have failed:
+/// the failure and exit callbacks run, just like they do when the scope panics.
+///
+/// The scope only counts toward [`current_depth`] while its future is being polled, and while its callbacks run,
+/// since the future may be suspended, or move to another thread, in between.
+/// The callbacks run by dropping an unfinished future are not counted.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_async;
+/// use std::cell::Cell;
+///
+/// async fn run(done: &Cell<bool>) -> Option<u32> {
+///     scoped_async(|guard| {
+///         Box::pin(async move {
+///             guard.on_scope_success(done, |d| d.set(true));
+///             async { 5 }.await.into()
+///         })
+///     })
+///     .await
+/// }
+/// ```
+pub async fn scoped_async<'a, R: Failure>(
+    scope: impl for<'g> FnOnce(&'g mut Guard<'a>) -> Pin<Box<dyn Future<Output = R> + 'g>>,
+) -> R {
+    let mut panic_guard = PanicGuard {
+        guard: Guard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    // Enter the depth for every synchronous section, never across an `.await`.
+    let mut future = {
+        let _depth = Depth::enter();
+        scope(&mut *guard)
+    };
+    let ret = core::future::poll_fn(|cx| {
+        let _depth = Depth::enter();
+        future.as_mut().poll(cx)
+    })
+    .await;
+    drop(future);
+
+    let _depth = Depth::enter();
+    guard.finish(Outcome::of(&ret));
+    ret
+}
+
+pub type ScopeResult<E> = Result<(), E>;
+
+/// Schedules a block or expression to run on a scope's exit.
+///
+/// `defer!(guard, body)` is shorthand for `guard.on_scope_exit_fn(move || { body; })`.
+/// Note that the body captures by move, so borrow what should not be moved beforehand.
+///
+/// # Examples
+/// ```
+/// use scoped::{defer, scoped};
+/// use std::cell::RefCell;
+///
+/// let log = &RefCell::new(vec![]);
+///
+/// scoped(|guard| {
+///     defer!(guard, {
+///         log.borrow_mut().push("closed");
+///     });
+///     defer!(guard, log.borrow_mut().push("flushed"));
+///
+///     Some(())
+/// });
+///
+/// assert_eq!(*log.borrow(), vec!["flushed", "closed"]);
+/// ```
+#[macro_export]
+macro_rules! defer {
+    ($guard:expr, $body:expr $(,)?) => {
+        $guard.on_scope_exit_fn(move || {
+            $body;
+        })
+    };
+}
+
+/// Schedules a block or expression to run on a scope's success.
+///
+/// `defer_success!(guard, body)` is shorthand for `guard.on_scope_success_fn(move || { body; })`.
+///
+/// # Examples
+/// ```
+/// use scoped::{defer_success, scoped};
+/// use std::cell::Cell;
+///
+/// let committed = &Cell::new(false);
+///
+/// scoped(|guard| -> Result<(), ()> {
+///     defer_success!(guard, committed.set(true));
+///     Ok(())
+/// });
+///
+/// assert!(committed.get());
+/// ```
+#[macro_export]
+macro_rules! defer_success {
+    ($guard:expr, $body:expr $(,)?) => {
+        $guard.on_scope_success_fn(move || {
+            $body;
+        })
+    };
+}
+
+/// Schedules a block or expression to run on a scope's failure.
+///
+/// `defer_failure!(guard, body)` is shorthand for `guard.on_scope_failure_fn(move || { body; })`.
+///
+/// # Examples
+/// ```
+/// use scoped::{defer_failure, scoped};
+/// use std::cell::Cell;
+///
+/// let rolled_back = &Cell::new(false);
+///
+/// scoped(|guard| -> Result<(), ()> {
+///     defer_failure!(guard, {
+///         rolled_back.set(true);
+///     });
+///     Err(())
+/// });
+///
+/// assert!(rolled_back.get());
+/// ```
+#[macro_export]
+macro_rules! defer_failure {
+    ($guard:expr, $body:expr $(,)?) => {
+        $guard.on_scope_failure_fn(move || {
+            $body;
+        })
+    };
+}
+
+/// Executes a block as a scope returning a [`ScopeResult`].
+///
+/// `scoped!(guard => { body })` is shorthand for `scoped(|guard| -> ScopeResult<_> { body })`,
+/// so the body can `return Err(..)` early and end in `Ok(())` without spelling out the closure's return type.
+/// The error type is inferred from the body. The `?` operator converts its errors with `From`,
+/// which leaves the error type open, so name it with `scoped!(guard: Error => { body })` when using `?`.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped;
+/// use std::cell::Cell;
+///
+/// let rolled_back = Cell::new(false);
+/// let ret = scoped!(guard => {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     if rolled_back.get() {
+///         return Err("already rolled back");
+///     }
+///     Ok(())
+/// });
+///
+/// assert_eq!(ret, Ok(()));
+/// assert!(!rolled_back.get());
+/// ```
+///
+/// With `?`, failing early:
+/// ```
+/// use scoped::scoped;
+/// use std::cell::Cell;
+/// use std::num::ParseIntError;
+///
+/// let rolled_back = Cell::new(false);
+/// let ret = scoped!(guard: ParseIntError => {
+///     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+///     let n: u32 = "four".parse()?;
+///     assert_eq!(n, 4, "not reached");
+///     Ok(())
+/// });
+///
+/// assert!(ret.is_err());
+/// assert!(rolled_back.get());
+/// ```
+///
+/// For simple scopes, the callbacks can be declared as blocks next to the body instead,
+/// with `scoped! { @body => { .. }, on_success => { .. }, on_failure => { .. }, on_exit => { .. } }`.
+/// The `@` sets this form apart from the one above, so a guard may still be named `body`.
+/// Each of the callback blocks is optional, but they have to be given in this order.
+/// They are scheduled as callbacks taking no item before the body runs,
+/// so they can borrow the same locals the body uses, but not the values the body computes.
+/// Like those callbacks, the blocks have to evaluate to `()`.
+/// The body returns any [`Failure`], and has no access to the guard.
+/// ```
+/// use scoped::scoped;
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+/// let ret = scoped! {
+///     @body => {
+///         log.borrow_mut().push("writing");
+///         Ok::<_, &str>(3)
+///     },
+///     on_success => { log.borrow_mut().push("committed") },
+///     on_failure => { log.borrow_mut().push("rolled back") },
+///     on_exit => { log.borrow_mut().push("closed") },
+/// };
+///
+/// assert_eq!(ret, Ok(3));
+/// assert_eq!(*log.borrow(), vec!["writing", "committed", "closed"]);
+/// ```
+///
+/// The failure path, leaving out the success block:
+/// ```
+/// use scoped::scoped;
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+/// let ret = scoped! {
+///     @body => { None::<u32> },
+///     on_failure => { log.borrow_mut().push("rolled back") },
+///     on_exit => { log.borrow_mut().push("closed") }
+/// };
+///
+/// assert_eq!(ret, None);
+/// assert_eq!(*log.borrow(), vec!["rolled back", "closed"]);
+/// ```
+#[macro_export]
+macro_rules! scoped {
+    (
+        @body => $body:block
+        $(, on_success => $success:block)?
+        $(, on_failure => $failure:block)?
+        $(, on_exit => $exit:block)?
+        $(,)?
+    ) => {
+        $crate::scoped(|guard| {
+            $(guard.on_scope_success_fn(|| $success);)?
+            $(guard.on_scope_failure_fn(|| $failure);)?
+            $(guard.on_scope_exit_fn(|| $exit);)?
+            $body
+        })
+    };
+    ($guard:ident => $body:block) => {
+        $crate::scoped(|$guard| -> $crate::ScopeResult<_> { $body })
+    };
+    ($guard:ident : $err:ty => $body:block) => {
+        $crate::scoped(|$guard| -> $crate::ScopeResult<$err> { $body })
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::{println, vec};
+
+    #[test]
+    fn test_list() {
+        let mut v = vec![1, 2, 3, 4, 5];
+        let scope = scoped(|guard| {
+            let v = guard.on_scope_success(&mut v, |v| {
+                println!("SUCCES!");
+
+                assert_eq!(*v, vec![1, 2, 3, 4, 5, 6, 7]);
+
+                v.push(10);
+            });
+
+            let boxed = guard.on_scope_exit(Box::new(1), move |boxed| {
+                assert_eq!(*boxed, 12);
+            });
+
+            v.push(6);
+            v.push(7);
+
+            **boxed = 12;
+
+            Some(10)
+        });
+
+        assert_eq!(scope, Some(10));
+    }
+
+    #[test]
+    fn test_fn_once() {
+        use std::sync::mpsc;
+
+        let (tx, rx) = mpsc::channel();
+        let message = String::from("done");
+
+        scoped(|guard| {
+            guard.on_scope_exit(tx, move |tx| {
+                tx.send(message).unwrap();
+            });
+
+            Some(())
+        });
+
+        assert_eq!(rx.recv().unwrap(), "done");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_panic_runs_failure_and_exit() {
+        use std::cell::Cell;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let success = Cell::new(false);
+        let failure = Cell::new(false);
+        let exit = Cell::new(false);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            scoped(|guard| -> Option<()> {
+                guard.on_scope_success(&success, |s| s.set(true));
+                guard.on_scope_failure(&failure, |f| f.set(true));
+                guard.on_scope_exit(&exit, |e| e.set(true));
+
+                panic!("boom");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(!success.get());
+        assert!(failure.get());
+        assert!(exit.get());
+    }
+
+    #[test]
+    fn main_test() {
+        use std::cell::Cell;
+
+        let number = Cell::new(0);
+
+        let n = scoped(|guard| {
+            let n = Some(1);
+
+            guard.on_scope_success(&number, move |b| {
+                assert!(2 == b.get());
+                b.set(0);
+            });
+
+            guard.on_scope_failure(&number, move |b| {
+                b.set(-1);
+            });
 
             guard.on_scope_exit(&number, move |b| {
                 b.set(0);
             });
 
-            number.set(2);
+            number.set(2);
+
+            n
+        });
+
+        assert!(number.get() == 0);
+        assert_eq!(n, Some(1));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_catch_unwind_resumes_panic() {
+        use std::cell::Cell;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let order = RefCell::new(vec![]);
+        let success = Cell::new(false);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            scoped_catch_unwind(|guard| -> Result<(), ()> {
+                guard.on_scope_success(&success, |s| s.set(true));
+                guard.on_scope_failure(&order, |o| o.borrow_mut().push("failure"));
+                guard.on_scope_exit(&order, |o| o.borrow_mut().push("exit"));
+
+                panic!("boom");
+            })
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        assert!(!success.get());
+        assert_eq!(*order.borrow(), vec!["failure", "exit"]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_timed() {
+        use std::thread;
+        use std::time::Duration;
+
+        let (ret, timing) = scoped_timed(|guard| {
+            guard.on_scope_success_fn(|| thread::sleep(Duration::from_millis(5)));
+            thread::sleep(Duration::from_millis(5));
+            true
+        });
+
+        assert!(ret);
+        assert!(timing.body >= Duration::from_millis(5));
+        assert!(timing.callbacks >= Duration::from_millis(5));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_catch_unwind_without_panic() {
+        use std::cell::Cell;
+
+        let success = Cell::new(false);
+
+        let ret = scoped_catch_unwind(|guard| {
+            guard.on_scope_success(&success, |s| s.set(true));
+            Some(5)
+        });
+
+        assert_eq!(ret, Some(5));
+        assert!(success.get());
+    }
+
+    #[test]
+    fn test_fn_variants() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            guard.on_scope_exit_fn(|| log.borrow_mut().push("exit"));
+
+            Some(())
+        });
+
+        scoped(|guard| {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            guard.on_scope_exit_fn(|| log.borrow_mut().push("exit"));
+
+            None::<()>
+        });
+
+        assert_eq!(*log.borrow(), vec!["success", "exit", "failure", "exit"]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let (first, _) = guard.on_scope_exit_with_id(&log, |l| l.borrow_mut().push(1));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(2));
+            let (success, _) = guard.on_scope_success_with_id(&log, |l| l.borrow_mut().push(3));
+
+            assert!(guard.cancel(first));
+            assert!(!guard.cancel(first));
+            assert!(guard.cancel(success));
+
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![2]);
+    }
+
+    #[test]
+    fn test_pending() {
+        scoped(|guard| {
+            guard.on_scope_exit_fn(|| ());
+            guard.on_scope_exit_fn(|| ());
+            guard.on_scope_exit_fn(|| ());
+            guard.on_scope_failure_fn(|| ());
+
+            assert_eq!(guard.pending_exit(), 3);
+            assert_eq!(guard.pending_failure(), 1);
+            assert_eq!(guard.pending_success(), 0);
+
+            Some(())
+        });
+    }
+
+    #[test]
+    fn test_bool() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            false
+        });
+
+        assert!(!ret);
+        assert_eq!(*log.borrow(), vec!["failure"]);
+
+        scoped(|guard| {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            true
+        });
+
+        assert_eq!(*log.borrow(), vec!["failure", "success"]);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| -> i32 {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            0
+        });
+
+        scoped(|guard| -> i32 {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            -1
+        });
+
+        scoped(|guard| -> u8 {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            0
+        });
+
+        scoped(|guard| -> u8 {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            2
+        });
+
+        assert_eq!(
+            *log.borrow(),
+            vec!["success", "failure", "success", "failure"]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped(|guard| -> ControlFlow<(), i32> {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            ControlFlow::Continue(1)
+        });
+        assert_eq!(ret, ControlFlow::Continue(1));
+
+        let ret = scoped(|guard| -> ControlFlow<(), i32> {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            ControlFlow::Break(())
+        });
+        assert_eq!(ret, ControlFlow::Break(()));
+
+        assert_eq!(*log.borrow(), vec!["success", "failure"]);
+    }
+
+    #[test]
+    fn test_poll() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            Poll::Ready(3)
+        });
+        assert_eq!(ret, Poll::Ready(3));
+
+        let ret = scoped(|guard| -> Poll<i32> {
+            guard.on_scope_success_fn(|| log.borrow_mut().push("success"));
+            guard.on_scope_failure_fn(|| log.borrow_mut().push("failure"));
+            Poll::Pending
+        });
+        assert_eq!(ret, Poll::Pending);
+
+        assert_eq!(*log.borrow(), vec!["success", "failure"]);
+    }
+
+    #[test]
+    fn test_exit_with_outcome() {
+        let outcomes = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_exit_with_outcome(&outcomes, |o, outcome| o.borrow_mut().push(outcome));
+            Some(())
+        });
+
+        scoped(|guard| {
+            guard.on_scope_exit_with_outcome(&outcomes, |o, outcome| o.borrow_mut().push(outcome));
+            None::<()>
+        });
+
+        assert_eq!(*outcomes.borrow(), vec![Outcome::Success, Outcome::Failure]);
+    }
+
+    /// Returns a waker that does nothing when woken.
+    fn noop_waker() -> std::task::Waker {
+        use std::sync::Arc;
+        use std::task::Wake;
+
+        struct Noop;
+
+        impl Wake for Noop {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        Arc::new(Noop).into()
+    }
+
+    /// Polls `fut` to completion on the current thread.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        use std::task::Context;
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+
+        loop {
+            if let Poll::Ready(ret) = fut.as_mut().poll(&mut cx) {
+                return ret;
+            }
+        }
+    }
+
+    /// A future that is pending once, before becoming ready.
+    fn yield_now() -> impl Future<Output = ()> {
+        let mut yielded = false;
+        std::future::poll_fn(move |cx| {
+            if yielded {
+                Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+    }
+
+    #[test]
+    fn test_async() {
+        let log = &RefCell::new(vec![]);
+
+        let ret = block_on(scoped_async(|guard| {
+            Box::pin(async move {
+                guard.on_scope_exit(log, |l| l.borrow_mut().push("exit"));
+                yield_now().await;
+                guard.on_scope_success(log, |l| l.borrow_mut().push("success"));
+                guard.on_scope_failure(log, |l| l.borrow_mut().push("failure"));
+                Ok::<_, ()>(1)
+            })
+        }));
+
+        assert_eq!(ret, Ok(1));
+        assert_eq!(*log.borrow(), vec!["success", "exit"]);
+
+        let ret = block_on(scoped_async(|guard| {
+            Box::pin(async move {
+                guard.on_scope_failure(log, |l| l.borrow_mut().push("failure"));
+                yield_now().await;
+                Err::<(), _>(())
+            })
+        }));
+
+        assert_eq!(ret, Err(()));
+        assert_eq!(*log.borrow(), vec!["success", "exit", "failure"]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_async_depth() {
+        use std::task::Context;
+
+        let depths = &RefCell::new(vec![]);
+        let scope = |name: &'static str| {
+            scoped_async(move |guard| {
+                Box::pin(async move {
+                    guard.on_scope_exit_fn(move || {
+                        depths.borrow_mut().push((name, current_depth()))
+                    });
+                    depths.borrow_mut().push((name, current_depth()));
+                    yield_now().await;
+                    depths.borrow_mut().push((name, current_depth()));
+                    Some(())
+                })
+            })
+        };
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut a = Box::pin(scope("a"));
+        let mut b = Box::pin(scope("b"));
+
+        assert!(a.as_mut().poll(&mut cx).is_pending());
+        assert!(b.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(current_depth(), 0);
+        assert!(a.as_mut().poll(&mut cx).is_ready());
+        assert!(b.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(current_depth(), 0);
+
+        let expected = [("a", 1), ("b", 1), ("a", 1), ("a", 1), ("b", 1), ("b", 1)];
+        assert_eq!(*depths.borrow(), expected);
+    }
+
+    #[test]
+    fn test_async_cancelled() {
+        use std::task::Context;
+
+        let log = &RefCell::new(vec![]);
+
+        let mut fut = Box::pin(scoped_async(|guard| {
+            Box::pin(async move {
+                guard.on_scope_success(log, |l| l.borrow_mut().push("success"));
+                guard.on_scope_failure(log, |l| l.borrow_mut().push("failure"));
+                guard.on_scope_exit(log, |l| l.borrow_mut().push("exit"));
+                yield_now().await;
+                Some(())
+            })
+        }));
+
+        let waker = noop_waker();
+        assert!(fut
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        drop(fut);
+
+        assert_eq!(*log.borrow(), vec!["failure", "exit"]);
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let guard = Guard::with_capacity(1, 2, 3);
+        assert!(guard.on_scope_success.inner.borrow().capacity() >= 1);
+        assert!(guard.on_scope_failure.inner.borrow().capacity() >= 2);
+        assert!(guard.on_scope_exit.inner.borrow().capacity() >= 3);
+
+        let count = Cell::new(0);
+        let ret = scoped_with_capacity(0, 0, 10, |guard| {
+            for _ in 0..10 {
+                guard.on_scope_exit(&count, |c| c.set(c.get() + 1));
+            }
+            Some(())
+        });
+
+        assert_eq!(ret, Some(()));
+        assert_eq!(count.get(), 10);
+    }
+
+    #[test]
+    fn test_default_does_not_allocate() {
+        let guard = Guard::default();
+        assert_eq!(guard.on_scope_success.inner.borrow().capacity(), 0);
+        assert_eq!(guard.on_scope_failure.inner.borrow().capacity(), 0);
+        assert_eq!(guard.on_scope_exit.inner.borrow().capacity(), 0);
+    }
+
+    #[test]
+    fn test_exit_order() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.exit_order(Order::Fifo);
+            guard.on_scope_success(&log, |l| l.borrow_mut().push(1));
+            guard.on_scope_success(&log, |l| l.borrow_mut().push(2));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(3));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(4));
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![2, 1, 3, 4]);
+    }
+
+    #[test]
+    fn test_run_exit_now() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(1));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(2));
+
+            guard.run_exit_now();
+            assert_eq!(*log.borrow(), vec![2, 1]);
+            assert_eq!(guard.pending_exit(), 0);
+
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(3));
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn test_peek() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let first = guard.on_scope_exit_peekable(1, |i| log.borrow_mut().push(i));
+            let second = guard.on_scope_success_peekable(2, |i| log.borrow_mut().push(i));
+
+            assert_eq!(guard.peek(first), Some(&1));
+            assert_eq!(guard.peek(second), Some(&2));
+
+            assert!(guard.cancel(second.id()));
+            assert_eq!(guard.peek(second), None);
+
+            scoped(|other| {
+                assert_eq!(other.peek(first), None);
+                Some(())
+            });
+
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![1]);
+    }
+
+    // Keeps references into several items alive while the queues grow and get reallocated,
+    // which is what `cargo +nightly miri test` needs to see to check the aliasing rules.
+    #[test]
+    fn test_item_references_stay_valid() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let first = guard.on_scope_exit(vec![1], |v| log.borrow_mut().extend(v));
+            let second = guard.on_scope_success(vec![2], |v| log.borrow_mut().extend(v));
+
+            for i in 0..32 {
+                guard.on_scope_exit(i, |_| {});
+            }
+            let peekable = guard.on_scope_exit_peekable(3, |i| log.borrow_mut().push(i));
+
+            first.push(10);
+            second.push(20);
+            assert_eq!(guard.peek(peekable), Some(&3));
+            first.push(11);
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![2, 20, 3, 1, 10, 11]);
+    }
+
+    #[test]
+    fn test_debug_pending() {
+        scoped(|guard| {
+            guard.on_scope_success_debug("saved", drop);
+            guard.on_scope_exit(3, drop);
+            *guard.on_scope_exit_debug(1, drop) += 1;
+
+            let report = guard.debug_pending();
+            let items: Vec<_> = report
+                .items
+                .iter()
+                .map(|p| (p.when, p.item.as_deref()))
+                .collect();
+            assert_eq!(
+                items,
+                vec![
+                    (When::Success, Some("\"saved\"")),
+                    (When::Exit, None),
+                    (When::Exit, Some("2")),
+                ]
+            );
+            Some(())
+        });
+    }
+
+    #[test]
+    fn test_with_predicate() {
+        let log = RefCell::new(vec![]);
+
+        // `Some(0)` is a success according to its `Failure` impl, but not according to the predicate.
+        let ret = scoped_with_predicate(
+            |guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("success"));
+                guard.on_scope_failure(&log, |l| l.borrow_mut().push("failure"));
+                Some(0)
+            },
+            |ret| *ret == Some(0),
+        );
+
+        assert_eq!(ret, Some(0));
+        assert_eq!(*log.borrow(), vec!["failure"]);
+    }
+
+    #[test]
+    fn test_deep_result() {
+        let ok: DeepResult<(), (), ()> = DeepResult::new(Ok(Ok(())));
+        let inner: DeepResult<(), (), ()> = Ok(Err(())).into();
+        let outer: DeepResult<(), (), ()> = DeepResult(Err(()));
+
+        assert!(!ok.is_error());
+        assert!(inner.is_error());
+        assert!(outer.is_error());
+        assert_eq!(inner.into_inner(), Ok(Err(())));
+    }
+
+    #[test]
+    fn test_limit() {
+        let ran = Cell::new(0);
+
+        scoped_with_limit(2, |guard| {
+            guard.on_scope_exit_fn(|| ran.set(ran.get() + 1));
+            guard.on_scope_success(&ran, |r| r.set(r.get() + 1));
+            assert!(guard.try_on_scope_exit(5, drop).is_err());
+
+            guard.run_exit_now();
+            assert!(guard
+                .try_on_scope_exit(&ran, |r| r.set(r.get() + 1))
+                .is_ok());
+            Some(())
+        });
+
+        assert_eq!(ran.get(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_limit_panics() {
+        let result = std::panic::catch_unwind(|| {
+            scoped_with_limit(3, |guard| {
+                for _ in 0..4 {
+                    guard.on_scope_failure_fn(|| {});
+                }
+                Some(())
+            })
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_on_scope() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            for &when in &[When::Success, When::Failure, When::Exit] {
+                guard.on_scope(when, &log, move |l| l.borrow_mut().push(when));
+            }
+            None::<()>
+        });
+
+        assert_eq!(*log.borrow(), vec![When::Failure, When::Exit]);
+    }
+
+    #[test]
+    fn test_finish() {
+        let log = RefCell::new(vec![]);
+
+        for &ok in &[true, false] {
+            scoped(|guard| {
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("exit"));
+                guard.on_scope_finish(&log, |l, succeeded| {
+                    l.borrow_mut()
+                        .push(if succeeded { "finished" } else { "aborted" })
+                });
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("success"));
+                guard.on_scope_failure(&log, |l| l.borrow_mut().push("failure"));
+                ok
+            });
+        }
+
+        assert_eq!(
+            *log.borrow(),
+            vec!["success", "finished", "exit", "failure", "aborted", "exit"]
+        );
+    }
+
+    #[test]
+    fn test_collect() {
+        let (ret, collected) = scoped_collect(|guard| {
+            *guard.on_scope_exit_map(1, |n| n + 1) = 2;
+            guard.on_scope_exit_fn(|| {});
+            guard.on_scope_exit_map("a", String::from);
+            true
+        });
+
+        assert!(ret);
+        assert_eq!(collected.len(), 2);
+        assert_eq!(collected[0].downcast_ref::<String>().unwrap(), "a");
+        assert_eq!(collected[1].downcast_ref::<i32>(), Some(&3));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_result_alias() {
+        use std::string::ToString;
+
+        type AnyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+        let failed = Cell::new(false);
+        let ret: AnyResult<()> = scoped(|guard| {
+            guard.on_scope_failure(&failed, |f| f.set(true));
+            Err("not found".into())
+        });
+
+        assert_eq!(ret.unwrap_err().to_string(), "not found");
+        assert!(failed.get());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_trait_object_item() {
+        use std::io::{self, Write};
+
+        struct Flushes<'f>(&'f Cell<u32>);
+
+        impl Write for Flushes<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        let flushes = Cell::new(0);
+        let mut writer = Flushes(&flushes);
+
+        scoped(|guard| {
+            let w = guard.on_scope_exit(&mut writer as &mut dyn Write, |w| {
+                w.flush().ok();
+            });
+            w.write_all(b"through the scheduled item").unwrap();
+            Some(())
+        });
+
+        assert_eq!(flushes.get(), 1);
+        // The borrow of `writer` ended with the scope.
+        writer.flush().unwrap();
+        assert_eq!(flushes.get(), 2);
+    }
+
+    #[test]
+    fn test_shared() {
+        let log = RefCell::new(vec![]);
+
+        fn helper<'a>(guard: &Guard<'a>, log: &'a RefCell<Vec<i32>>, n: i32) {
+            guard.on_scope_exit(log, move |l| l.borrow_mut().push(n));
+        }
+
+        scoped_shared(|guard| {
+            let (a, b) = (guard, guard);
+            helper(a, &log, 1);
+            helper(b, &log, 2);
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![2, 1]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_builder() {
+        let log = RefCell::new(vec![]);
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            ScopeBuilder::new()
+                .exit_order(Order::Fifo)
+                .catch_panic(true)
+                .run(|guard| -> Option<()> {
+                    guard.on_scope_exit(&log, |l| l.borrow_mut().push(1));
+                    guard.on_scope_exit(&log, |l| l.borrow_mut().push(2));
+                    guard.on_scope_failure(&log, |l| l.borrow_mut().push(0));
+                    panic!("oh no")
+                })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(*log.borrow(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_run_phase() {
+        let log = RefCell::new(vec![]);
+        let mut guard = Guard::default();
+
+        for i in 0..3 {
+            guard.on_scope_success(&log, move |l| l.borrow_mut().push((i, "success")));
+            guard.on_scope_failure(&log, move |l| l.borrow_mut().push((i, "failure")));
+            guard.on_scope_exit(&log, move |l| l.borrow_mut().push((i, "exit")));
+            guard.run_phase(i != 1);
+
+            assert_eq!(guard.pending_success(), 0);
+            assert_eq!(guard.pending_failure(), 0);
+            assert_eq!(guard.pending_exit(), 0);
+        }
+
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                (0, "success"),
+                (0, "exit"),
+                (1, "failure"),
+                (1, "exit"),
+                (2, "success"),
+                (2, "exit"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failure_with_error() {
+        use std::string::ToString;
+
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped_result(|guard| {
+            guard.on_scope_failure_with_error(&log, |l, e: Option<&String>| {
+                l.borrow_mut().push(e.cloned())
+            });
+            guard.on_scope_failure_with_error(&log, |l, e: Option<&i32>| {
+                l.borrow_mut().push(e.map(|e| e.to_string()))
+            });
+            Err::<(), _>(String::from("broken pipe"))
+        });
+
+        assert_eq!(ret, Err(String::from("broken pipe")));
+        assert_eq!(*log.borrow(), vec![None, Some(String::from("broken pipe"))]);
+    }
+
+    #[test]
+    fn test_drop_guard() {
+        let log = RefCell::new(vec![]);
+
+        'outer: for i in 0..3 {
+            for j in 0..3 {
+                let guard = Guard::default().into_drop_guard();
+                guard.on_scope_exit(&log, move |l| l.borrow_mut().push((i, j)));
+                guard.on_scope_success(&log, |l| l.borrow_mut().push((9, 9)));
+
+                if j == 1 {
+                    continue 'outer;
+                }
+                if i == 1 {
+                    break 'outer;
+                }
+            }
+        }
+
+        assert_eq!(*log.borrow(), vec![(0, 0), (0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn test_report() {
+        let (ret, report) = scoped_report(|guard| {
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_failure_fn(|| {});
+            guard.on_scope_exit_fn(|| {});
+            guard.run_exit_now();
+            guard.on_scope_exit_fn(|| {});
+            true
+        });
+
+        assert!(ret);
+        assert_eq!(
+            report,
+            ScopeReport {
+                outcome: Outcome::Success,
+                success_ran: 2,
+                failure_ran: 0,
+                finish_ran: 0,
+                exit_ran: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_owned() {
+        fn schedule<'a, T: Clone + 'static>(
+            guard: &Guard<'a>,
+            value: T,
+        ) -> std::rc::Rc<RefCell<Vec<T>>> {
+            let out = std::rc::Rc::new(RefCell::new(vec![]));
+            let sink = out.clone();
+            guard.on_scope_exit_owned(value, move |v| sink.borrow_mut().push(v));
+            out
+        }
+
+        let mut out = None;
+        scoped(|guard| {
+            let sink = schedule(guard, String::from("a"));
+            guard
+                .on_scope_success_owned(String::from("b"), {
+                    let sink = sink.clone();
+                    move |v| sink.borrow_mut().push(v)
+                })
+                .push('!');
+            guard.on_scope_failure_owned((), |()| unreachable!());
+            out = Some(sink);
+            Some(())
+        });
+
+        assert_eq!(*out.unwrap().borrow(), vec!["b!", "a"]);
+    }
+
+    #[test]
+    fn test_exit_each() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let log = &log;
+            guard.on_scope_exit_each(0..5, move |i| log.borrow_mut().push(i));
+            assert_eq!(guard.pending_exit(), 5);
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_exit_panic_runs_remaining() {
+        let log = RefCell::new(vec![]);
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            scoped(|guard| {
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("second"));
+                guard.on_scope_exit_fn(|| panic!("first"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("third"));
+                Some(())
+            })
+        }));
+
+        assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(), "first");
+        assert_eq!(*log.borrow(), vec!["third", "second"]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_catch_panics_opt_in() {
+        let log = RefCell::new(vec![]);
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            scoped(|guard| {
+                guard.catch_panics(When::Success, true);
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("success"));
+                guard.on_scope_success_fn(|| panic!("oh no"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("exit"));
+                Some(())
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(*log.borrow(), vec!["success", "exit"]);
+    }
+
+    #[test]
+    fn test_then() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped_then(
+            |guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("first success"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("first exit"));
+                true
+            },
+            |guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("second success"));
+                true
+            },
+        );
+        assert_eq!(ret, Chained::Done(true, true));
+        assert_eq!(
+            *log.borrow(),
+            vec!["second success", "first success", "first exit"]
+        );
+
+        log.borrow_mut().clear();
+        let ret = scoped_then(
+            |guard| {
+                guard.on_scope_failure(&log, |l| l.borrow_mut().push("first failure"));
+                false
+            },
+            |_| -> bool { unreachable!() },
+        );
+        assert_eq!(ret, Chained::FirstFailed(false));
+        assert_eq!(*log.borrow(), vec!["first failure"]);
+
+        log.borrow_mut().clear();
+        let ret = scoped_then(
+            |guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("first success"));
+                guard.on_scope_failure(&log, |l| l.borrow_mut().push("first failure"));
+                true
+            },
+            |guard| {
+                guard.on_scope_failure(&log, |l| l.borrow_mut().push("second failure"));
+                guard.mark_failing();
+                true
+            },
+        );
+        assert_eq!(ret, Chained::SecondFailed(true, true));
+        assert_eq!(*log.borrow(), vec!["second failure", "first failure"]);
+
+        log.borrow_mut().clear();
+        let ret = scoped_then(
+            |guard| {
+                guard.on_scope_failure(&log, |l| l.borrow_mut().push("first failure"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("first exit"));
+                Outcome::Indeterminate
+            },
+            |_| -> bool { unreachable!() },
+        );
+        assert_eq!(ret, Chained::FirstFailed(Outcome::Indeterminate));
+        assert_eq!(*log.borrow(), vec!["first exit"]);
+
+        log.borrow_mut().clear();
+        let ret = scoped_then(
+            |guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("first success"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("first exit"));
+                true
+            },
+            |guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("second success"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("second exit"));
+                Outcome::Indeterminate
+            },
+        );
+        assert_eq!(ret, Chained::SecondFailed(true, Outcome::Indeterminate));
+        assert_eq!(*log.borrow(), vec!["second exit", "first exit"]);
+    }
+
+    #[test]
+    fn test_aggregate_results() {
+        let mixed = vec![Ok(1), Err(()), Ok(3)];
+        let oks: Vec<Result<i32, ()>> = vec![Ok(1), Ok(2)];
+        let errs: Vec<Result<i32, ()>> = vec![Err(()), Err(())];
+
+        assert!(AllOk::new(mixed.clone()).is_error());
+        assert!(!AllOk::new(oks).is_error());
+        assert!(!AllOk::<i32, ()>::new(vec![]).is_error());
+
+        assert!(!AnyOk::new(mixed.clone()).is_error());
+        assert!(AnyOk::new(errs).is_error());
+        assert!(AnyOk::<i32, ()>::new(vec![]).is_error());
+
+        assert_eq!(AnyOk::from(mixed.clone()).into_inner(), mixed);
+    }
+
+    #[test]
+    fn test_with_state() {
+        let (ret, log) = scoped_with_state(RefCell::new(vec![]), |guard, log| {
+            guard.on_scope_success_fn(move || log.borrow_mut().push("success"));
+            guard.on_scope_exit_fn(move || log.borrow_mut().push("exit"));
+            log.borrow_mut().push("body");
+            true
+        });
+
+        assert!(ret);
+        assert_eq!(log.into_inner(), vec!["body", "success", "exit"]);
+    }
+
+    #[test]
+    fn test_total_order() {
+        let log = RefCell::new(vec![]);
+
+        for &ok in &[true, false] {
+            log.borrow_mut().clear();
+            scoped(|guard| {
+                let log = &log;
+                guard.on_scope_exit_fn(move || log.borrow_mut().push("exit 1"));
+                guard.on_scope_success_fn(move || log.borrow_mut().push("success 1"));
+                guard.on_scope_failure_fn(move || log.borrow_mut().push("failure 1"));
+                guard.on_scope_exit_fn(move || log.borrow_mut().push("exit 2"));
+                guard.on_scope_success_fn(move || log.borrow_mut().push("success 2"));
+                guard.on_scope_failure_fn(move || log.borrow_mut().push("failure 2"));
+                guard.on_scope_exit_fn(move || log.borrow_mut().push("exit 3"));
+                ok
+            });
+
+            let expected = if ok {
+                vec!["success 2", "success 1", "exit 3", "exit 2", "exit 1"]
+            } else {
+                vec!["failure 2", "failure 1", "exit 3", "exit 2", "exit 1"]
+            };
+            assert_eq!(*log.borrow(), expected);
+        }
+    }
+
+    #[test]
+    fn test_infallible_result() {
+        let log = RefCell::new(vec![]);
+
+        let ret: Result<u8, Infallible> = scoped(|guard| {
+            guard.on_scope_success(&log, |l| l.borrow_mut().push("success"));
+            guard.on_scope_failure(&log, |l| l.borrow_mut().push("failure"));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push("exit"));
+            Ok(1)
+        });
+
+        assert_eq!(ret, Ok(1));
+        assert_eq!(*log.borrow(), vec!["success", "exit"]);
+    }
+
+    #[test]
+    fn test_retry() {
+        let log = RefCell::new(vec![]);
+        let attempt = Cell::new(0);
+
+        let ret = scoped_retry(5, |guard| {
+            let n = attempt.get() + 1;
+            attempt.set(n);
+
+            let log = &log;
+            guard.on_scope_success_fn(move || log.borrow_mut().push(("success", n)));
+            guard.on_scope_failure_fn(move || log.borrow_mut().push(("failure", n)));
+            guard.on_scope_exit_fn(move || log.borrow_mut().push(("exit", n)));
+            n == 3
+        });
+
+        assert!(ret);
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                ("failure", 1),
+                ("failure", 2),
+                ("success", 3),
+                ("exit", 3),
+                ("exit", 2),
+                ("exit", 1),
+            ]
+        );
+
+        attempt.set(0);
+        assert!(!scoped_retry(1, |_| {
+            attempt.set(attempt.get() + 1);
+            false
+        }));
+        assert_eq!(attempt.get(), 2);
+    }
+
+    #[test]
+    fn test_push_boxed() {
+        struct Record<'l>(&'l RefCell<Vec<Outcome>>);
+
+        impl Defer for Record<'_> {
+            fn call(self: Box<Self>, outcome: Outcome) {
+                self.0.borrow_mut().push(outcome);
+            }
+        }
+
+        let log = RefCell::new(vec![]);
+        let mut deferring = Deferring::new();
+        deferring.push_boxed(Box::new(Record(&log)));
+        deferring.push(1, |_| {});
+        deferring.push_boxed(Box::new(Record(&log)));
+        deferring.execute(Outcome::Failure);
+
+        assert_eq!(*log.borrow(), vec![Outcome::Failure, Outcome::Failure]);
+    }
+
+    #[test]
+    fn test_handles() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let (a, b) = (guard.handle(), guard.handle());
+            a.on_scope_exit(&log, |l| l.borrow_mut().push("a"));
+            let item = b.on_scope_success(vec![], |v: Vec<&str>| log.borrow_mut().extend(v));
+            a.on_scope_failure_fn(|| unreachable!());
+            item.push("b");
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_tuples() {
+        let ok = Ok::<(), ()>(());
+        let err = Err::<(), ()>(());
+
+        assert!(!(ok, ok).is_error());
+        assert!((ok, err).is_error());
+        assert!((err, ok).is_error());
+        assert!(!(ok, true, Some(1)).is_error());
+        assert!((ok, true, Some(1), 1).is_error());
+    }
+
+    // Exiting would end the test run, so this test runs itself again in a child process that does exit.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_exit_process() {
+        use std::process::Command;
+
+        if std::env::var_os("SCOPED_EXIT_PROCESS_CHILD").is_some() {
+            scoped(|guard| -> Infallible {
+                guard.on_scope_failure_fn(|| println!("failure callback ran"));
+                guard.on_scope_exit_fn(|| println!("exit callback ran"));
+                guard.exit_process(3)
+            });
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::test_exit_process", "--nocapture"])
+            .env("SCOPED_EXIT_PROCESS_CHILD", "1")
+            .output()
+            .unwrap();
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert!(stdout.contains("failure callback ran\nexit callback ran\n"));
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let log = RefCell::new(vec![]);
+        let guard = Guard::default();
+
+        guard.on_scope_success(&log, |l| l.borrow_mut().push("success"));
+        guard.on_scope_failure(&log, |l| l.borrow_mut().push("failure"));
+        guard.on_scope_exit(&log, |l| l.borrow_mut().push("exit"));
+        guard.on_scope_finish(&log, |l, ok| {
+            l.borrow_mut().push(if ok { "finish" } else { "abort" })
+        });
+        *guard.on_scope_exit(vec![], |v| log.borrow_mut().extend(v)) = vec!["item"];
+
+        guard.on_scope_exit_priority(1, &log, |l| l.borrow_mut().push("first exit"));
+
+        let (mut success, failure, mut finish, mut exit) = guard.into_parts();
+        drop(failure);
+        success.execute(Outcome::Success);
+        finish.execute(Outcome::Failure);
+        exit.execute(Outcome::Failure);
+
+        assert_eq!(
+            *log.borrow(),
+            vec!["success", "abort", "first exit", "item", "exit"]
+        );
+
+        log.borrow_mut().clear();
+        let guard = Guard::default();
+        guard.on_scope_finish(&log, |l, _| l.borrow_mut().push("finish"));
+        guard.on_scope_exit_priority(1, &log, |l| l.borrow_mut().push("exit"));
+
+        // An indeterminate outcome only runs the exit callbacks.
+        let (_, _, finish, mut exit) = guard.into_parts();
+        drop(finish);
+        exit.execute(Outcome::Indeterminate);
+        assert_eq!(*log.borrow(), vec!["exit"]);
+    }
+
+    #[test]
+    fn test_exit_shared() {
+        let seen: Rc<RefCell<Vec<i32>>> = Rc::default();
+        let sink = Rc::clone(&seen);
+        let shared: Rc<RefCell<dyn FnMut(i32)>> =
+            Rc::new(RefCell::new(move |n| sink.borrow_mut().push(n)));
+
+        scoped(|guard| {
+            for n in 1..=3 {
+                guard.on_scope_exit_shared(n, &shared);
+            }
+            *guard.on_scope_exit_shared(0, &shared) = 4;
+            Some(())
+        });
+
+        assert_eq!(*seen.borrow(), vec![4, 3, 2, 1]);
+        assert_eq!(Rc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn test_nonzero_success() {
+        assert!(NonzeroSuccess(0u32).is_error());
+        assert!(NonzeroSuccess(0u64).is_error());
+        assert!(NonzeroSuccess(0usize).is_error());
+        assert!(!NonzeroSuccess(1u32).is_error());
+        assert!(!NonzeroSuccess(u64::MAX).is_error());
+        assert!(!NonzeroSuccess(7usize).is_error());
+    }
+
+    #[test]
+    fn test_shared_item_order() {
+        let state = Rc::new(RefCell::new(vec![]));
+        let seen = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let seen = &seen;
+            guard.on_scope_exit(Rc::clone(&state), move |s| {
+                seen.borrow_mut().push(s.borrow().clone());
+                s.borrow_mut().push("a");
+            });
+            guard.on_scope_exit(Rc::clone(&state), move |s| {
+                seen.borrow_mut().push(s.borrow().clone());
+                s.borrow_mut().push("b");
+            });
+            Some(())
+        });
+
+        assert_eq!(*seen.borrow(), vec![vec![], vec!["b"]]);
+        assert_eq!(*state.borrow(), vec!["b", "a"]);
+    }
 
-            n
+    #[test]
+    fn test_failure_with() {
+        let ran = Cell::new(None);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_success(&ran, |r| r.set(Some(Outcome::Success)));
+            guard.on_scope_failure(&ran, |r| r.set(Some(Outcome::Failure)));
+            FailureWith::new(3, |n: &i32| n % 2 == 1)
         });
 
-        assert!(number.get() == 0);
-        assert_eq!(n, Some(1));
+        assert_eq!(ran.get(), Some(Outcome::Failure));
+        assert_eq!(ret.into_inner(), 3);
+        assert!(!FailureWith(4, |n: &i32| n % 2 == 1).is_error());
+    }
+
+    #[test]
+    fn test_execute_keeps_capacity() {
+        let mut deferring = Deferring::new();
+        for i in 0..8 {
+            deferring.push(i, drop);
+        }
+
+        deferring.execute(Outcome::Success);
+        let capacity = deferring.inner.borrow().capacity();
+        assert!(capacity >= 8);
+
+        for i in 0..8 {
+            deferring.push(i, drop);
+        }
+        assert_eq!(deferring.inner.borrow().capacity(), capacity);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_exit_if_elapsed() {
+        use std::time::Duration;
+
+        let ran = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_exit_if_elapsed("slow", Duration::from_millis(1), |s| {
+                ran.borrow_mut().push(s)
+            });
+            guard.on_scope_exit_if_elapsed("fast", Duration::from_secs(3600), |s| {
+                ran.borrow_mut().push(s)
+            });
+            std::thread::sleep(Duration::from_millis(5));
+            Some(())
+        });
+
+        assert_eq!(*ran.borrow(), vec!["slow"]);
+    }
+
+    #[test]
+    fn test_keep() {
+        let (ret, stats) = scoped_keep(|guard| {
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_failure_fn(|| {});
+            guard.on_scope_exit_fn(|| {});
+            None::<()>
+        });
+
+        assert_eq!(ret, None);
+        assert_eq!(stats.report.outcome, Outcome::Failure);
+        assert_eq!((stats.report.failure_ran, stats.report.exit_ran), (1, 1));
+        assert_eq!((stats.discarded, stats.pending, stats.panics), (2, 0, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_keep_counts_panics() {
+        let exits = Cell::new(0);
+
+        let (_, stats) = scoped_keep(|guard| {
+            guard.on_scope_success_fn(|| panic!("commit failed"));
+            guard.on_scope_exit_fn(|| exits.set(exits.get() + 1));
+            guard.on_scope_exit_fn(|| panic!("close failed"));
+            Some(())
+        });
+
+        assert_eq!(exits.get(), 1);
+        assert_eq!(stats.report.success_ran, 1);
+        assert_eq!(stats.panics, 2);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_failure_with_source() {
+        use std::string::ToString;
+
+        let seen = RefCell::new(vec![]);
+
+        let ret = scoped_boxed_err(|guard| {
+            guard.on_scope_failure_with_source(&seen, |seen, source| {
+                seen.borrow_mut().push(source.map(ToString::to_string))
+            });
+            Err::<(), _>("disk full".into())
+        });
+        assert_eq!(ret.unwrap_err().to_string(), "disk full");
+
+        scoped(|guard| {
+            guard.on_scope_failure_with_source(&seen, |seen, source| {
+                seen.borrow_mut().push(source.map(ToString::to_string))
+            });
+            None::<()>
+        });
+
+        assert_eq!(*seen.borrow(), vec![Some("disk full".to_string()), None]);
+    }
+
+    #[test]
+    fn test_mark_failing() {
+        let ran = Cell::new(None);
+
+        let (ret, report) = scoped_report(|guard| {
+            guard.on_scope_success(&ran, |r| r.set(Some(Outcome::Success)));
+            guard.on_scope_failure(&ran, |r| r.set(Some(Outcome::Failure)));
+            assert!(!guard.is_marked_failing());
+            guard.mark_failing();
+            assert!(guard.is_marked_failing());
+            true
+        });
+
+        assert!(ret);
+        assert_eq!(report.outcome, Outcome::Failure);
+        assert_eq!(ran.get(), Some(Outcome::Failure));
+    }
+
+    #[test]
+    fn test_delegating_impls() {
+        let ran = Cell::new(false);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_success(&ran, |r| r.set(true));
+            &Ok::<_, ()>(5)
+        });
+        assert_eq!(ret, &Ok(5));
+        assert!(ran.get());
+
+        let boxed: Box<dyn Failure> = Box::new(Err::<(), ()>(()));
+        assert!(boxed.is_error());
+        assert!(!<&mut Option<i32>>::is_error(&&mut Some(1)));
+        assert!(Cow::<Option<i32>>::Owned(None).is_error());
+        assert!(!Cow::Borrowed(&true).is_error());
+    }
+
+    #[test]
+    fn test_exit_keyed() {
+        let ran = RefCell::new(vec![]);
+        let replaced = Rc::new(());
+
+        scoped(|guard| {
+            guard.on_scope_exit_keyed("flush", Rc::clone(&replaced), |_| {
+                panic!("replaced callback ran")
+            });
+            assert_eq!(Rc::strong_count(&replaced), 2);
+
+            guard.on_scope_exit_keyed(1, "other", |s| ran.borrow_mut().push(s));
+            *guard.on_scope_exit_keyed("flush", "first", |s| ran.borrow_mut().push(s)) = "last";
+            assert_eq!(Rc::strong_count(&replaced), 1);
+            assert_eq!(guard.pending_exit(), 2);
+            Some(())
+        });
+
+        assert_eq!(*ran.borrow(), vec!["last", "other"]);
+
+        ran.borrow_mut().clear();
+        scoped(|guard| {
+            let mut other = Guard::default();
+            other.on_scope_exit_keyed("flush", "absorbed", |_| panic!("replaced callback ran"));
+            guard.absorb(other);
+            guard.on_scope_exit_keyed("flush", "replacing", |s| ran.borrow_mut().push(s));
+            assert_eq!(guard.pending_exit(), 1);
+
+            guard.run_exit_now();
+            guard.on_scope_exit_keyed("flush", "after the checkpoint", |s| {
+                ran.borrow_mut().push(s)
+            });
+            guard.clear_exit();
+            guard.on_scope_exit_keyed("flush", "after clearing", |s| ran.borrow_mut().push(s));
+            Some(())
+        });
+
+        assert_eq!(*ran.borrow(), vec!["replacing", "after clearing"]);
+    }
+
+    #[test]
+    fn test_unrun_drop_order() {
+        struct Noisy<'l>(&'static str, &'l RefCell<Vec<&'static str>>);
+
+        impl Drop for Noisy<'_> {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let captured = Noisy("closure", &log);
+            let (id, _) = guard.on_scope_exit_with_id(Noisy("item", &log), move |_| drop(captured));
+            assert!(guard.cancel(id));
+            assert_eq!(*log.borrow(), vec!["item", "closure"]);
+
+            guard.on_scope_success(Noisy("first", &log), drop);
+            guard.on_scope_success(Noisy("second", &log), drop);
+            None::<()>
+        });
+
+        assert_eq!(*log.borrow(), vec!["item", "closure", "first", "second"]);
+    }
+
+    #[test]
+    fn test_failure_err() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped_result(|guard| {
+            guard.on_scope_failure_err(&log, |l, e: &i32| l.borrow_mut().push(*e));
+            guard.on_scope_failure_err(&log, |_, _: &u8| panic!("not the error type"));
+            Err::<(), _>(7)
+        });
+        assert_eq!(ret, Err(7));
+
+        let ret = scoped_result(|guard| {
+            guard.on_scope_failure_err(&log, |_, _: &i32| panic!("scope did not fail"));
+            Ok::<_, i32>(1)
+        });
+        assert_eq!(ret, Ok(1));
+
+        scoped(|guard| {
+            guard.on_scope_failure_err(&log, |_, _: &i32| panic!("error unknown to scoped"));
+            false
+        });
+
+        assert_eq!(*log.borrow(), vec![7]);
+    }
+
+    #[test]
+    fn test_poll_result() {
+        let ready_ok: PollResult<(), ()> = PollResult::new(Poll::Ready(Ok(())));
+        let ready_err: PollResult<(), ()> = Poll::Ready(Err(())).into();
+        let pending: PollResult<(), ()> = PollResult(Poll::Pending);
+
+        assert!(!ready_ok.is_error());
+        assert!(ready_err.is_error());
+        assert!(pending.is_error());
+        assert!(!Poll::Ready(Err::<(), ()>(())).is_error());
+        assert_eq!(ready_err.into_inner(), Poll::Ready(Err(())));
+    }
+
+    #[test]
+    fn test_on_result() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_result(
+                Ok::<_, i32>("ok"),
+                |v| log.borrow_mut().push(v),
+                |_| panic!("stored result was ok"),
+            );
+            guard.on_result(
+                Err::<&str, _>(2),
+                |_| panic!("stored result was an error"),
+                |_| log.borrow_mut().push("err"),
+            );
+            None::<()>
+        });
+
+        assert_eq!(*log.borrow(), vec!["err", "ok"]);
+    }
+
+    #[test]
+    fn test_outcome_as_result() {
+        let ran = Cell::new(None);
+
+        let ret = scoped(|guard| -> Outcome {
+            guard.on_scope_success(&ran, |r| r.set(Some(Outcome::Success)));
+            guard.on_scope_failure(&ran, |r| r.set(Some(Outcome::Failure)));
+            Err::<(), ()>(()).into()
+        });
+
+        assert_eq!(ret, Outcome::Failure);
+        assert_eq!(ran.get(), Some(Outcome::Failure));
+        assert_eq!(Outcome::from(true), Outcome::Success);
+        assert_eq!(Outcome::from(Ok::<_, ()>(1)), Outcome::Success);
+        assert!(!Outcome::Success.is_error());
+    }
+
+    #[test]
+    fn test_indeterminate() {
+        struct Unknown;
+
+        impl Failure for Unknown {
+            fn is_error(&self) -> bool {
+                false
+            }
+
+            fn outcome(&self) -> Outcome {
+                Outcome::Indeterminate
+            }
+        }
+
+        let exit = Cell::new(None);
+
+        let (_, report) = scoped_report(|guard| {
+            guard.on_scope_success_fn(|| panic!("scope did not succeed"));
+            guard.on_scope_failure_fn(|| panic!("scope did not fail"));
+            guard.on_scope_finish((), |_, _| panic!("scope did not finish"));
+            guard.on_scope_exit_with_outcome(&exit, |e, outcome| e.set(Some(outcome)));
+            Unknown
+        });
+
+        assert_eq!(exit.get(), Some(Outcome::Indeterminate));
+        assert_eq!(report.outcome, Outcome::Indeterminate);
+        assert_eq!(report.exit_ran, 1);
+        assert_eq!(scoped(|_| &Unknown).outcome(), Outcome::Indeterminate);
+    }
+
+    #[test]
+    fn test_replace_item() {
+        use std::string::ToString;
+
+        let received = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            let id =
+                guard.on_scope_exit_peekable("old".to_string(), |s| received.borrow_mut().push(s));
+            assert_eq!(
+                guard.replace_item(id, "new".to_string()).as_deref(),
+                Some("old")
+            );
+            assert_eq!(guard.peek(id).map(String::as_str), Some("new"));
+
+            let cancelled = guard.on_scope_exit_peekable(String::new(), drop);
+            assert!(guard.cancel(cancelled.id()));
+            assert_eq!(guard.replace_item(cancelled, "late".to_string()), None);
+            Some(())
+        });
+
+        assert_eq!(*received.borrow(), vec!["new"]);
+    }
+
+    #[test]
+    fn test_range_success() {
+        assert!(!RangeSuccess(200, 200..=399).is_error());
+        assert!(!RangeSuccess::new(399, 200..=399).is_error());
+        assert!(RangeSuccess(199, 200..=399).is_error());
+        assert!(RangeSuccess(404, 200..=399).is_error());
+        assert!(RangeSuccess(f64::NAN, 0.0..=1.0).is_error());
+    }
+
+    #[test]
+    fn test_run_and_count() {
+        let mut deferring = Deferring::new();
+        for i in 0..3 {
+            deferring.push(i, drop);
+        }
+        assert_eq!(deferring.execute_and_report(Outcome::Success), 3);
+        assert_eq!(deferring.execute_and_report(Outcome::Success), 0);
+
+        let mut guard = Guard::default();
+        guard.on_scope_success_fn(|| {});
+        guard.on_scope_success_fn(|| {});
+        guard.on_scope_failure_fn(|| {});
+        guard.on_scope_finish((), |_, _| {});
+        guard.on_scope_exit_fn(|| {});
+
+        let counts = guard.run_and_count(Outcome::Success);
+        assert_eq!(counts.outcome, Outcome::Success);
+        assert_eq!(
+            (
+                counts.success_ran,
+                counts.failure_ran,
+                counts.finish_ran,
+                counts.exit_ran
+            ),
+            (2, 0, 1, 1)
+        );
+        assert_eq!(guard.pending(), 0);
+    }
+
+    #[test]
+    fn test_with_resource() {
+        let held = Cell::new(0);
+        let acquire = || {
+            held.set(held.get() + 1);
+            held.get()
+        };
+        let release = |_| held.set(held.get() - 1);
+
+        scoped(|guard| {
+            assert_eq!(*guard.with_resource(acquire, release), 1);
+            assert_eq!(*guard.with_resource(acquire, release), 2);
+            assert_eq!(held.get(), 2);
+            None::<()>
+        });
+
+        assert_eq!(held.get(), 0);
+    }
+
+    #[test]
+    fn test_shared_pointer_impls() {
+        let ran = Cell::new(None);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_success(&ran, |r| r.set(Some(Outcome::Success)));
+            guard.on_scope_failure(&ran, |r| r.set(Some(Outcome::Failure)));
+            Rc::new(Err::<(), ()>(()))
+        });
+        assert_eq!(*ret, Err(()));
+        assert_eq!(ran.get(), Some(Outcome::Failure));
+
+        scoped(|guard| {
+            guard.on_scope_success(&ran, |r| r.set(Some(Outcome::Success)));
+            Arc::new(Ok::<(), ()>(()))
+        });
+        assert_eq!(ran.get(), Some(Outcome::Success));
+    }
+
+    #[test]
+    fn test_scoped_guard() {
+        let ran = Cell::new(0);
+
+        let guard = Guard::default();
+        guard.on_scope_exit(&ran, |r| r.set(r.get() + 1));
+        guard.on_scope_success(&ran, |_| panic!("scope did not succeed"));
+
+        scoped_guard(guard, |guard| {
+            assert_eq!(guard.pending(), 2);
+            false
+        });
+
+        assert_eq!(ran.get(), 1);
+    }
+
+    #[test]
+    fn test_exit_priority() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_exit_priority(1, &log, |l| l.borrow_mut().push("high 1"));
+            guard.on_scope_exit_priority(-1, &log, |l| l.borrow_mut().push("low"));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push("default"));
+            guard.on_scope_exit_priority(1, &log, |l| l.borrow_mut().push("high 2"));
+            Some(())
+        });
+        assert_eq!(*log.borrow(), vec!["high 2", "high 1", "default", "low"]);
+
+        log.borrow_mut().clear();
+        scoped(|guard| {
+            guard.exit_order(Order::Fifo);
+            guard.on_scope_exit_priority(1, &log, |l| l.borrow_mut().push("high 1"));
+            guard.on_scope_exit_priority(-1, &log, |l| l.borrow_mut().push("low"));
+            guard.on_scope_exit_priority(1, &log, |l| l.borrow_mut().push("high 2"));
+            Some(())
+        });
+        assert_eq!(*log.borrow(), vec!["high 1", "high 2", "low"]);
+    }
+
+    #[test]
+    fn test_exit_with_value() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped_value(|guard| {
+            guard.on_scope_exit_with_value(&log, |l, v: Option<&i32>| {
+                l.borrow_mut().push(v.copied())
+            });
+            guard.on_scope_exit_with_value(&log, |l, v: Option<&u8>| {
+                l.borrow_mut().push(v.map(|_| 0))
+            });
+            Ok::<_, ()>(3)
+        });
+        assert_eq!(ret, Ok(3));
+        assert_eq!(*log.borrow(), vec![None, Some(3)]);
+
+        log.borrow_mut().clear();
+        let ret = scoped_value(|guard| {
+            guard.on_scope_exit_with_value(&log, |l, v: Option<&i32>| {
+                l.borrow_mut().push(v.copied())
+            });
+            Err::<i32, _>(())
+        });
+        assert_eq!(ret, Err(()));
+        assert_eq!(*log.borrow(), vec![None]);
+
+        log.borrow_mut().clear();
+        scoped_result(|guard| {
+            guard.on_scope_exit_with_value(&log, |l, v: Option<&i32>| {
+                l.borrow_mut().push(v.copied())
+            });
+            Ok::<_, ()>(3)
+        })
+        .unwrap();
+        assert_eq!(*log.borrow(), vec![None]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_current_depth() {
+        let depths = RefCell::new(vec![]);
+
+        assert_eq!(current_depth(), 0);
+        scoped(|guard| {
+            guard.on_scope_exit(&depths, |d| d.borrow_mut().push(current_depth()));
+            scoped_result(|_| {
+                depths.borrow_mut().push(current_depth());
+                Ok::<_, ()>(())
+            })
+        })
+        .unwrap();
+        let _ =
+            std::panic::catch_unwind(|| scoped(|_| -> Option<()> { panic!("leaving the scope") }));
+
+        assert_eq!(current_depth(), 0);
+        assert_eq!(*depths.borrow(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_exit_ref() {
+        struct Flush<'a>(&'a RefCell<Vec<i32>>, i32);
+        impl Drop for Flush<'_> {
+            fn drop(&mut self) {
+                self.0.borrow_mut().push(self.1);
+            }
+        }
+
+        let log = RefCell::new(vec![]);
+        let (_, collected) = scoped_collect(|guard| {
+            guard.on_scope_exit_ref(Flush(&log, 1), |f| f.1 += 10);
+            guard.on_scope_exit_ref_keep(5, |n| *n *= 2);
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![11]);
+        assert_eq!(collected[0].downcast_ref::<i32>(), Some(&10));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_armed_deferring() {
+        let mut deferring = Deferring::new();
+        deferring.arm();
+        deferring.push((), drop);
+        deferring.execute(Outcome::Success);
+        drop(deferring);
+
+        let dropped = std::panic::catch_unwind(|| {
+            let deferring = Deferring::new();
+            deferring.arm();
+            deferring.push((), drop);
+        });
+        assert!(dropped.is_err());
+    }
+
+    #[test]
+    fn test_msg_outcome() {
+        let failed = Cell::new(0);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_failure(&failed, |f| f.set(f.get() + 1));
+            MsgOutcome::new("")
+        });
+        assert_eq!(ret.into_inner(), "");
+        assert_eq!(failed.get(), 0);
+
+        let ret = scoped(|guard| {
+            guard.on_scope_failure(&failed, |f| f.set(f.get() + 1));
+            MsgOutcome::new("disk full")
+        });
+        assert_eq!(ret.into_inner(), "disk full");
+        assert_eq!(failed.get(), 1);
+    }
+
+    #[test]
+    fn test_exit_with_counts() {
+        let counts = Cell::new(PhaseCounts::default());
+
+        scoped(|guard| {
+            guard.on_scope_exit_with_counts(&counts, |c, n| c.set(n));
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_failure_fn(|| {});
+            Some(())
+        });
+        assert_eq!(
+            counts.get(),
+            PhaseCounts {
+                success_ran: 2,
+                failure_ran: 0,
+            }
+        );
+
+        scoped(|guard| {
+            guard.on_scope_exit_with_counts(&counts, |c, n| c.set(n));
+            guard.on_scope_success_fn(|| {});
+            guard.on_scope_failure_fn(|| {});
+            None::<()>
+        });
+        assert_eq!(
+            counts.get(),
+            PhaseCounts {
+                success_ran: 0,
+                failure_ran: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_exit_with_counts_early() {
+        let counts = Cell::new(None);
+
+        let mut guard = Guard::default();
+        guard.on_scope_exit_with_counts(&counts, |c, n| c.set(Some(n)));
+        guard.on_scope_success_fn(|| {});
+        guard.run_phase(true);
+        assert_eq!(counts.get().map(|n| n.success_ran), Some(1));
+
+        guard.on_scope_exit_with_counts(&counts, |c, n| c.set(Some(n)));
+        guard.run_exit_now();
+        assert_eq!(counts.get(), Some(PhaseCounts::default()));
+    }
+
+    #[test]
+    fn test_retry_counts() {
+        let counts = RefCell::new(vec![]);
+        let attempts = Cell::new(0);
+
+        scoped_retry(1, |guard| {
+            attempts.set(attempts.get() + 1);
+            guard.on_scope_exit_with_counts(&counts, |c, n| c.borrow_mut().push(n.failure_ran));
+            guard.on_scope_failure_fn(|| {});
+            guard.on_scope_failure_fn(|| {});
+            if attempts.get() < 2 {
+                Err(())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+
+        assert_eq!(*counts.borrow(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_scoped_macro_forms() {
+        let log = RefCell::new(vec![]);
+
+        let ret = scoped!(body => {
+            body.on_scope_exit(&log, |l| l.borrow_mut().push("guard named body"));
+            Ok::<_, ()>(())
+        });
+        assert_eq!(ret, Ok(()));
+
+        let ret = scoped! {
+            @body => { Some(1) },
+            on_exit => { log.borrow_mut().push("exit block") }
+        };
+        assert_eq!(ret, Some(1));
+
+        assert_eq!(*log.borrow(), vec!["guard named body", "exit block"]);
+    }
+
+    #[test]
+    fn test_exit_if() {
+        let flag = Cell::new(false);
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_exit_if(&log, || flag.get(), |l| l.borrow_mut().push("flagged"));
+            guard.on_scope_exit_if(&log, || !flag.get(), |l| l.borrow_mut().push("unflagged"));
+
+            // The condition is checked at exit, so this flip counts.
+            flag.set(true);
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec!["flagged"]);
+    }
+
+    #[test]
+    fn test_absorb() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(1));
+
+            let other = Guard::default();
+            other.on_scope_exit(&log, |l| l.borrow_mut().push(2));
+            other.on_scope_exit(&log, |l| l.borrow_mut().push(3));
+            other.on_scope_failure(&log, |l| l.borrow_mut().push(4));
+            let (id, _) = other.on_scope_success_with_id(&log, |l| l.borrow_mut().push(5));
+
+            guard.absorb(other);
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(6));
+
+            assert!(guard.cancel(id));
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![6, 3, 2, 1]);
+    }
+
+    #[test]
+    fn test_clear() {
+        let log = RefCell::new(vec![]);
+
+        scoped(|guard| {
+            guard.on_scope_success(&log, |l| l.borrow_mut().push(1));
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(2));
+            guard.clear_exit();
+            assert_eq!(guard.pending_exit(), 0);
+            assert_eq!(guard.pending_success(), 1);
+
+            guard.clear();
+            assert_eq!(guard.pending_success(), 0);
+
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(3));
+            Some(())
+        });
+
+        assert_eq!(*log.borrow(), vec![3]);
     }
 }
@@ -0,0 +1,31 @@
+//! The commonly used items, for a single glob import.
+//!
+#![cfg_attr(feature = "derive", doc = "```")]
+#![cfg_attr(not(feature = "derive"), doc = "```ignore")]
+//! use scoped::prelude::*;
+//! use std::cell::Cell;
+//!
+//! #[derive(Failure)]
+//! enum Sync {
+//!     Done,
+//!     #[failure]
+//!     Conflict,
+//! }
+//!
+//! let rolled_back = Cell::new(false);
+//! scoped(|guard| {
+//!     guard.on_scope_failure(&rolled_back, |r| r.set(true));
+//!     defer!(guard, println!("closing"));
+//!     Sync::Conflict
+//! });
+//!
+//! assert!(rolled_back.get());
+//! ```
+//!
+//! Items that depend on a feature, like `#[derive(Failure)]` or [`scoped_catch_unwind`],
+//! are only exported if the feature is enabled.
+pub use crate::{defer, defer_failure, defer_success};
+pub use crate::{scoped, scoped_result, Failure, Guard, Outcome, ScopeResult, When};
+
+#[cfg(feature = "std")]
+pub use crate::{scoped_catch_unwind, scoped_sync, SyncGuard};
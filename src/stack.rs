@@ -0,0 +1,275 @@
+//! A guard storing its callbacks inline, for scopes that must not allocate.
+use crate::{Depth, Failure, Outcome, When};
+
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+
+/// The inline storage of a single callback: room for four pointers, aligned like one.
+type Storage = MaybeUninit<[usize; 4]>;
+
+/// A callback along with its item, as kept in a [`Storage`].
+/// Like the callbacks of a `Guard`, it drops its item before the closure.
+struct Callback<T, F> {
+    item: T,
+    dc: F,
+}
+
+/// Fails to compile when instantiated for a callback that does not fit into a [`Storage`].
+struct Fits<C>(PhantomData<C>);
+
+impl<C> Fits<C> {
+    const ASSERT: () = assert!(
+        mem::size_of::<C>() <= mem::size_of::<Storage>()
+            && mem::align_of::<C>() <= mem::align_of::<Storage>(),
+        "the item and the callback don't fit into a `StackGuard` slot"
+    );
+}
+
+/// Moves the `Callback<T, F>` out of `storage` and calls it.
+unsafe fn call<T, F: FnOnce(T)>(storage: *mut Storage) {
+    let Callback { item, dc } = ptr::read(storage.cast::<Callback<T, F>>());
+    dc(item)
+}
+
+/// Drops the `Callback<T, F>` in `storage` without calling it.
+unsafe fn drop_callback<T, F>(storage: *mut Storage) {
+    ptr::drop_in_place(storage.cast::<Callback<T, F>>());
+}
+
+struct Slot {
+    /// The phase the callback is scheduled for, or `None` once it ran or was dropped.
+    when: Option<When>,
+    call: unsafe fn(*mut Storage),
+    drop: unsafe fn(*mut Storage),
+    storage: Storage,
+}
+
+/// A guard that stores up to `N` callbacks inline, so scheduling never allocates.
+///
+/// Each callback, along with its item, has to fit into four pointers, and be aligned to at most a pointer,
+/// which holds for closures capturing a few references. Larger callbacks fail to compile:
+/// ```compile_fail
+/// use scoped::scoped_stack;
+///
+/// scoped_stack::<1, _>(|guard| {
+///     guard.on_scope_exit([0u64; 8], drop);
+///     Some(())
+/// });
+/// ```
+///
+/// `StackGuard` supports the success, failure and exit phases. Their callbacks run in the same order
+/// as with [`Guard`](crate::Guard): the success or failure callbacks, then the exit callbacks,
+/// each phase last scheduled first.
+pub struct StackGuard<'a, const N: usize> {
+    slots: UnsafeCell<[MaybeUninit<Slot>; N]>,
+    len: Cell<usize>,
+    marker: PhantomData<(&'a (), *mut ())>,
+}
+
+impl<'a, const N: usize> StackGuard<'a, N> {
+    fn new() -> Self {
+        Self {
+            // An array of `MaybeUninit`s needs no initialization.
+            slots: UnsafeCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
+            len: Cell::new(0),
+            marker: PhantomData,
+        }
+    }
+
+    /// Points to the slot at `index`, without borrowing the other slots,
+    /// whose items may be borrowed by the references the scheduling methods returned.
+    fn slot(&self, index: usize) -> *mut Slot {
+        debug_assert!(index < N);
+        unsafe {
+            self.slots
+                .get()
+                .cast::<MaybeUninit<Slot>>()
+                .add(index)
+                .cast()
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn push<T: 'a, F: FnOnce(T) + 'a>(&self, when: When, item: T, dc: F) -> &mut T {
+        #[allow(clippy::let_unit_value)]
+        let () = Fits::<Callback<T, F>>::ASSERT;
+
+        let index = self.len.get();
+        assert!(index < N, "a `StackGuard` holds at most {} callbacks", N);
+
+        let slot = self.slot(index);
+        unsafe {
+            slot.write(Slot {
+                when: Some(when),
+                call: call::<T, F>,
+                drop: drop_callback::<T, F>,
+                storage: MaybeUninit::uninit(),
+            });
+
+            let callback = ptr::addr_of_mut!((*slot).storage).cast::<Callback<T, F>>();
+            callback.write(Callback { item, dc });
+            self.len.set(index + 1);
+
+            // The slot is never written to again until the callback ran, which takes `&mut self`.
+            &mut *ptr::addr_of_mut!((*callback).item)
+        }
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's success.
+    ///
+    /// # Panics
+    /// Panics if `N` callbacks are already scheduled.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.push(When::Success, item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure.
+    ///
+    /// # Panics
+    /// Panics if `N` callbacks are already scheduled.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.push(When::Failure, item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit.
+    ///
+    /// # Panics
+    /// Panics if `N` callbacks are already scheduled.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit<T: 'a>(&self, item: T, dc: impl FnOnce(T) + 'a) -> &mut T {
+        self.push(When::Exit, item, dc)
+    }
+
+    /// Returns the number of callbacks that can still be scheduled.
+    pub fn remaining(&self) -> usize {
+        N - self.len.get()
+    }
+
+    /// Calls or drops the pending callbacks of the phase `when`, last scheduled first.
+    fn drain(&mut self, when: When, run: bool) {
+        for index in (0..self.len.get()).rev() {
+            let slot = self.slot(index);
+            unsafe {
+                if (*slot).when != Some(when) {
+                    continue;
+                }
+
+                // Mark the callback as gone first, so it is not touched again if it panics.
+                (*slot).when = None;
+                let storage = ptr::addr_of_mut!((*slot).storage);
+                if run {
+                    ((*slot).call)(storage);
+                } else {
+                    ((*slot).drop)(storage);
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Success => {
+                self.drain(When::Failure, false);
+                self.drain(When::Success, true);
+            }
+            Outcome::Failure => {
+                self.drain(When::Success, false);
+                self.drain(When::Failure, true);
+            }
+            Outcome::Indeterminate => {
+                self.drain(When::Success, false);
+                self.drain(When::Failure, false);
+            }
+        }
+
+        self.drain(When::Exit, true);
+        self.len.set(0);
+    }
+}
+
+/// Runs the callbacks that are still pending as if the scope failed, see `PanicGuard`.
+/// After a normal return they all ran already, so this does nothing.
+impl<'a, const N: usize> Drop for StackGuard<'a, N> {
+    fn drop(&mut self) {
+        self.finish(Outcome::Failure);
+    }
+}
+
+/// Executes the scope `scope` with a [`StackGuard`] holding up to `N` callbacks.
+///
+/// This behaves like [`scoped`](fn@crate::scoped), except that neither the guard nor scheduling a callback allocates.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_stack;
+/// use std::cell::RefCell;
+///
+/// let log = RefCell::new(vec![]);
+///
+/// scoped_stack::<4, _>(|guard| {
+///     guard.on_scope_exit(&log, |log| log.borrow_mut().push("closed"));
+///     guard.on_scope_success(&log, |log| log.borrow_mut().push("committed"));
+///     Some(())
+/// });
+///
+/// assert_eq!(*log.borrow(), vec!["committed", "closed"]);
+/// ```
+pub fn scoped_stack<'a, const N: usize, R: Failure>(
+    scope: impl FnOnce(&mut StackGuard<'a, N>) -> R,
+) -> R {
+    let _depth = Depth::enter();
+    let mut guard = StackGuard::new();
+    let ret = scope(&mut guard);
+    guard.finish(Outcome::of(&ret));
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_stack_order() {
+        let log = RefCell::new(Vec::new());
+        let dropped = Rc::new(());
+
+        let ret = scoped_stack::<5, _>(|guard| {
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(1));
+            guard.on_scope_failure(&log, |l| l.borrow_mut().push(2));
+            guard.on_scope_success(Rc::clone(&dropped), |_| panic!("scope did not succeed"));
+            *guard.on_scope_failure(&log, |l| l.borrow_mut().push(3)) = &log;
+            guard.on_scope_exit(&log, |l| l.borrow_mut().push(4));
+            assert_eq!(guard.remaining(), 0);
+            None::<()>
+        });
+
+        assert_eq!(ret, None);
+        assert_eq!(*log.borrow(), vec![3, 2, 4, 1]);
+        assert_eq!(Rc::strong_count(&dropped), 1);
+    }
+
+    #[test]
+    fn test_stack_unwind() {
+        let log = RefCell::new(Vec::new());
+
+        let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            scoped_stack::<2, _>(|guard| {
+                guard.on_scope_success(&log, |l| l.borrow_mut().push("success"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("exit"));
+                guard.on_scope_exit(&log, |l| l.borrow_mut().push("overflow"));
+                Some(())
+            })
+        }));
+
+        assert!(ret.is_err());
+        assert_eq!(*log.borrow(), vec!["exit"]);
+    }
+}
@@ -0,0 +1,266 @@
+//! A thread safe counterpart of [`Guard`](crate::Guard),
+//! for scheduling callbacks from multiple threads.
+use crate::{allocate, Defer, DeferCallback, Depth, Failure, Outcome, Scheduled};
+
+use alloc::vec::Vec;
+use std::sync::{Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+#[derive(Default)]
+struct SyncDeferring<'a> {
+    inner: Mutex<Vec<Scheduled<dyn Defer + Send + 'a>>>,
+}
+
+impl<'a> SyncDeferring<'a> {
+    #[allow(clippy::mut_from_ref)]
+    fn push<T: Send + 'a>(&self, item: T, closure: impl FnOnce(T) + Send + 'a) -> &mut T {
+        let (raw, item) = allocate(DeferCallback::new(item, move |item, _| closure(item)));
+        let deferred = unsafe { Scheduled::<dyn Defer + Send + 'a>::from_raw(raw) };
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(deferred);
+
+        // See `Deferring::schedule_with_outcome`, the same reasoning applies here.
+        // The lock only guards the vector, the item is never touched by another thread until execution.
+        unsafe { &mut *item.as_ptr() }
+    }
+
+    fn execute(&mut self, outcome: Outcome) {
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let v = core::mem::take(inner);
+        for d in v.into_iter().rev() {
+            d.into_box().call(outcome);
+        }
+    }
+}
+
+/// A guard that can be shared between threads, to schedule callbacks on from an outer scope.
+///
+/// Unlike [`Guard`](crate::Guard), `SyncGuard` is [`Sync`], so a `&SyncGuard` can be handed to
+/// scoped threads. This requires both the scheduled items and the callbacks to be [`Send`],
+/// as they may be scheduled on one thread while being run on another.
+///
+/// The callbacks run on the thread that called [`scoped_sync`], once the scope returned.
+#[derive(Default)]
+pub struct SyncGuard<'a> {
+    on_scope_success: SyncDeferring<'a>,
+    on_scope_failure: SyncDeferring<'a>,
+    on_scope_exit: SyncDeferring<'a>,
+}
+
+impl<'a> SyncGuard<'a> {
+    /// Schedules defered closure `dc` to run on a scope's success.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_success<T: Send + 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + Send + 'a,
+    ) -> &mut T {
+        self.on_scope_success.push(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's exit.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_exit<T: Send + 'a>(&self, item: T, dc: impl FnOnce(T) + Send + 'a) -> &mut T {
+        self.on_scope_exit.push(item, dc)
+    }
+
+    /// Schedules defered closure `dc` to run on a scope's failure.
+    #[allow(clippy::mut_from_ref)]
+    pub fn on_scope_failure<T: Send + 'a>(
+        &self,
+        item: T,
+        dc: impl FnOnce(T) + Send + 'a,
+    ) -> &mut T {
+        self.on_scope_failure.push(item, dc)
+    }
+
+    /// Schedules defered closure `dc`, which takes no item, to run on a scope's success.
+    pub fn on_scope_success_fn(&self, dc: impl FnOnce() + Send + 'a) {
+        self.on_scope_success.push((), move |()| dc());
+    }
+
+    /// Schedules defered closure `dc`, which takes no item, to run on a scope's exit.
+    pub fn on_scope_exit_fn(&self, dc: impl FnOnce() + Send + 'a) {
+        self.on_scope_exit.push((), move |()| dc());
+    }
+
+    /// Schedules defered closure `dc`, which takes no item, to run on a scope's failure.
+    pub fn on_scope_failure_fn(&self, dc: impl FnOnce() + Send + 'a) {
+        self.on_scope_failure.push((), move |()| dc());
+    }
+
+    fn finish(&mut self, outcome: Outcome) {
+        self.finish_branch(outcome);
+        self.on_scope_exit.execute(outcome);
+    }
+
+    /// Runs the success or failure callbacks depending on `outcome`, but not the exit callbacks.
+    fn finish_branch(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Success => {
+                self.on_scope_failure = SyncDeferring::default();
+                self.on_scope_success.execute(outcome);
+            }
+            Outcome::Failure => self.on_scope_failure.execute(outcome),
+            Outcome::Indeterminate => {
+                self.on_scope_success = SyncDeferring::default();
+                self.on_scope_failure = SyncDeferring::default();
+            }
+        }
+    }
+}
+
+/// Runs the remaining failure and exit callbacks when dropped, see `PanicGuard`.
+struct SyncPanicGuard<'a> {
+    guard: SyncGuard<'a>,
+}
+
+impl<'a> Drop for SyncPanicGuard<'a> {
+    fn drop(&mut self) {
+        self.guard.on_scope_failure.execute(Outcome::Failure);
+        self.guard.on_scope_exit.execute(Outcome::Failure);
+    }
+}
+
+/// Executes the scope `scope` with a [`SyncGuard`].
+///
+/// This behaves like [`scoped`](fn@crate::scoped), except that the guard can be shared with other threads,
+/// for example with [`std::thread::scope`].
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_sync;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::thread;
+///
+/// let cleaned = AtomicUsize::new(0);
+///
+/// scoped_sync(|guard| -> Result<(), ()> {
+///     let guard = &*guard;
+///     thread::scope(|s| {
+///         for _ in 0..4 {
+///             s.spawn(|| guard.on_scope_exit(&cleaned, |c| { c.fetch_add(1, Ordering::SeqCst); }));
+///         }
+///     });
+///     Ok(())
+/// });
+///
+/// assert_eq!(cleaned.load(Ordering::SeqCst), 4);
+/// ```
+pub fn scoped_sync<'a, R: Failure>(scope: impl FnOnce(&mut SyncGuard<'a>) -> R) -> R {
+    let _depth = Depth::enter();
+    let mut panic_guard = SyncPanicGuard {
+        guard: SyncGuard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+    guard.finish(Outcome::of(&ret));
+    ret
+}
+
+/// Executes the scope `scope` with a [`SyncGuard`], running its exit callbacks on a thread of their own.
+///
+/// The success or failure callbacks run on the calling thread, like with [`scoped_sync`].
+/// The exit callbacks are then moved to a newly spawned thread, so slow cleanups, like closing network connections,
+/// don't hold up the caller. Call [`JoinHandle::join`] on the returned handle to wait for them.
+///
+/// The exit callbacks still run in their usual order among themselves, but no longer in any order
+/// relative to what the calling thread does after this returns. As they may outlive the call,
+/// their items and closures must be `'static`.
+/// If the scope or one of the success or failure callbacks panics, the exit callbacks run on the calling thread instead.
+///
+/// # Examples
+/// ```
+/// use scoped::scoped_detached;
+///
+/// let (ret, cleanup) = scoped_detached(|guard| {
+///     guard.on_scope_exit_fn(|| println!("closing the connection"));
+///     Some(5)
+/// });
+///
+/// assert_eq!(ret, Some(5));
+/// cleanup.join().unwrap();
+/// ```
+pub fn scoped_detached<R: Failure>(
+    scope: impl FnOnce(&mut SyncGuard<'static>) -> R,
+) -> (R, JoinHandle<()>) {
+    let _depth = Depth::enter();
+    let mut panic_guard = SyncPanicGuard {
+        guard: SyncGuard::default(),
+    };
+    let guard = &mut panic_guard.guard;
+
+    let ret = scope(guard);
+    let outcome = Outcome::of(&ret);
+    guard.finish_branch(outcome);
+
+    let mut exit = core::mem::take(&mut guard.on_scope_exit);
+    let handle = thread::spawn(move || exit.execute(outcome));
+    (ret, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::vec;
+
+    #[test]
+    fn test_threads() {
+        let log = Mutex::new(vec![]);
+        let exits = AtomicUsize::new(0);
+
+        let ret = scoped_sync(|guard| {
+            let guard = &*guard;
+
+            thread::scope(|s| {
+                for i in 0..4 {
+                    let log = &log;
+                    let exits = &exits;
+                    s.spawn(move || {
+                        guard.on_scope_success(log, move |l| l.lock().unwrap().push(i));
+                        guard.on_scope_failure_fn(|| panic!("scope did not fail"));
+                        guard.on_scope_exit_fn(move || {
+                            exits.fetch_add(1, Ordering::SeqCst);
+                        });
+                    });
+                }
+            });
+
+            Some(())
+        });
+
+        assert_eq!(ret, Some(()));
+        assert_eq!(exits.load(Ordering::SeqCst), 4);
+
+        let mut log = log.into_inner().unwrap();
+        log.sort_unstable();
+        assert_eq!(log, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_detached() {
+        use std::sync::Arc;
+
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let (ret, handle) = scoped_detached(|guard| {
+            let exit_log = Arc::clone(&log);
+            guard.on_scope_exit_fn(move || exit_log.lock().unwrap().push(thread::current().id()));
+            guard.on_scope_failure(Arc::clone(&log), |l| {
+                l.lock().unwrap().push(thread::current().id())
+            });
+            Err::<(), ()>(())
+        });
+        handle.join().unwrap();
+
+        assert_eq!(ret, Err(()));
+        let log = log.lock().unwrap();
+        assert_eq!(log[0], thread::current().id());
+        assert_ne!(log[1], thread::current().id());
+    }
+}
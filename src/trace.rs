@@ -0,0 +1,112 @@
+//! A hook observing every callback as it runs, to trace cleanups in production.
+//!
+//! The hook is global, so it also sees the callbacks of guards it was not installed for.
+//! Forward the events to the logging framework of your choice:
+//! ```
+//! use scoped::{scoped, set_trace_hook, CallbackEvent};
+//!
+//! fn trace(event: &CallbackEvent) {
+//!     // e.g. `log::trace!("running callback {} of {:?}", event.index, event.when)`
+//!     println!("running callback {} of {:?}", event.index, event.when);
+//! }
+//!
+//! set_trace_hook(Some(trace));
+//! scoped(|guard| {
+//!     guard.on_scope_exit_fn(|| println!("cleaning up"));
+//!     Some(())
+//! });
+//! set_trace_hook(None);
+//! ```
+use crate::{Outcome, When};
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// A callback that is about to run, as reported to the hook installed with [`set_trace_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CallbackEvent {
+    /// The phase the callback was scheduled for,
+    /// or `None` for the callbacks of a standalone [`Deferring`](crate::Deferring).
+    pub when: Option<When>,
+
+    /// The position of the callback among the callbacks run together with it,
+    /// starting at 0 for the one that runs first.
+    pub index: usize,
+
+    /// The outcome passed to the callback.
+    pub outcome: Outcome,
+}
+
+/// The installed hook, a `fn(&CallbackEvent)`, or null if there is none.
+static HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Installs `hook` to be called right before every callback runs, replacing the previous hook.
+/// `None` removes the hook.
+pub fn set_trace_hook(hook: Option<fn(&CallbackEvent)>) {
+    let ptr = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut ());
+    HOOK.store(ptr, Ordering::Release);
+}
+
+/// Reports `event` to the installed hook, if any.
+pub(crate) fn callback(event: &CallbackEvent) {
+    let ptr = HOOK.load(Ordering::Acquire);
+    if !ptr.is_null() {
+        // Only `set_trace_hook` stores into `HOOK`, and it only stores `fn(&CallbackEvent)`s.
+        let hook = unsafe { core::mem::transmute::<*mut (), fn(&CallbackEvent)>(ptr) };
+        hook(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoped;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+    use std::{thread_local, vec};
+
+    thread_local! {
+        // Other tests run on other threads, so this only sees the callbacks of this test.
+        static EVENTS: RefCell<Vec<CallbackEvent>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(event: &CallbackEvent) {
+        EVENTS.with(|events| events.borrow_mut().push(*event));
+    }
+
+    /// Removes the hook when dropped, so it does not outlive a failing test either.
+    struct Unhook;
+
+    impl Drop for Unhook {
+        fn drop(&mut self) {
+            set_trace_hook(None);
+        }
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        set_trace_hook(Some(record));
+        let _unhook = Unhook;
+
+        scoped(|guard| {
+            guard.on_scope_failure_fn(|| {});
+            guard.on_scope_exit_fn(|| {});
+            guard.on_scope_exit_fn(|| {});
+            None::<()>
+        });
+
+        let event = |when, index| CallbackEvent {
+            when: Some(when),
+            index,
+            outcome: Outcome::Failure,
+        };
+        let events = EVENTS.with(|events| events.borrow().clone());
+        assert_eq!(
+            events,
+            vec![
+                event(When::Failure, 0),
+                event(When::Exit, 0),
+                event(When::Exit, 1),
+            ]
+        );
+    }
+}